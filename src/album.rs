@@ -0,0 +1,191 @@
+use std::fs::read_dir;
+use std::path::{Path, PathBuf};
+
+use claxon::FlacReader;
+use crate::mp3v0fs::{FLAC, has_extension};
+use crate::tags::Chapter;
+
+/// Name of the virtual MP3 holding a whole directory.
+pub const ALBUM_FILE_NAME: &'static str = "Album.mp3";
+
+/// A FLAC making up part of a whole-album MP3.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AlbumTrack {
+    pub flac_path: PathBuf,
+    pub title: String,
+    // Samples (per channel)
+    pub samples: u64
+}
+
+/// The FLACs of a directory in track order, to be encoded back to back into a single MP3.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Album {
+    pub tracks: Vec<AlbumTrack>,
+    pub sample_rate: u32
+}
+
+impl Album {
+
+    /// Collects the FLACs of a directory, ordered by disc number, track number and name. FLACs that
+    /// can't be read or are of an unknown length are left out. Returns None unless there are at
+    /// least 2 FLACs left sharing a sample rate and channel count, as they can't be encoded into one
+    /// stream otherwise.
+    pub fn scan(directory: &Path) -> Option<Album> {
+        let mut tracks: Vec<((u32, u32, PathBuf), AlbumTrack)> = Vec::new();
+        let mut format: Option<(u32, u32)> = None;
+
+        for dir_entry in read_dir(directory).ok()?.filter_map(|dir_entry| dir_entry.ok()) {
+            let flac_path = dir_entry.path();
+            if !has_extension(&flac_path, FLAC) {
+                continue;
+            }
+
+            let flac_reader = match FlacReader::open(&flac_path) {
+                Ok(flac_reader) => flac_reader,
+                Err(err) => {
+                    warn!("Leaving {:?} out of the album: {}", flac_path, err);
+                    continue;
+                }
+            };
+            let stream_info = flac_reader.streaminfo();
+            let samples = match stream_info.samples {
+                Some(samples) => samples,
+                None => {
+                    warn!("Leaving {:?} out of the album, its length is unknown", flac_path);
+                    continue;
+                }
+            };
+            let track_format = (stream_info.sample_rate, stream_info.channels);
+            if *format.get_or_insert(track_format) != track_format {
+                return None;
+            }
+
+            let title = flac_reader.get_tag("TITLE").next()
+                .map(String::from)
+                .unwrap_or_else(|| flac_path.file_stem().unwrap().to_string_lossy().into_owned());
+            let disc_number = flac_reader.get_tag("DISCNUMBER").next().and_then(parse_number).unwrap_or(0);
+            let track_number = flac_reader.get_tag("TRACKNUMBER").next().and_then(parse_number).unwrap_or(0);
+
+            tracks.push(((disc_number, track_number, flac_path.clone()), AlbumTrack {
+                flac_path,
+                title,
+                samples
+            }));
+        }
+
+        if tracks.len() < 2 {
+            return None;
+        }
+        tracks.sort_by(|(order, _), (other_order, _)| order.cmp(other_order));
+
+        Some(Album {
+            tracks: tracks.into_iter().map(|(_, track)| track).collect(),
+            sample_rate: format?.0
+        })
+    }
+
+    /// Samples (per channel) in the whole album.
+    pub fn total_samples(&self) -> u64 {
+        self.tracks.iter().map(|track| track.samples).sum()
+    }
+
+    /// Chapters marking where each track starts.
+    pub fn chapters(&self) -> Vec<Chapter> {
+        let mut chapters = Vec::with_capacity(self.tracks.len());
+        let mut start: u64 = 0;
+
+        for track in &self.tracks {
+            let end = start + track.samples;
+            chapters.push(Chapter {
+                title: track.title.clone(),
+                start: self.milliseconds(start),
+                end: self.milliseconds(end)
+            });
+            start = end;
+        }
+
+        chapters
+    }
+
+    fn milliseconds(&self, samples: u64) -> u32 {
+        (samples * 1000 / u64::from(self.sample_rate).max(1)) as u32
+    }
+}
+
+/// Builds the vorbis comments of the whole-album MP3 from those of its first track. Per-track
+/// comments are dropped and the album title is used as the title.
+pub fn album_comments(first_track_comments: &[(String, String)]) -> Vec<(String, String)> {
+    let mut comments: Vec<(String, String)> = first_track_comments.iter()
+        .filter(|(key, _)| match key.to_uppercase().as_ref() {
+            "TITLE" | "TRACKNUMBER" | "TRACKTOTAL" | "TOTALTRACKS" | "DISCNUMBER" | "LYRICS"
+            | "UNSYNCEDLYRICS" | "ISRC" | "MUSICBRAINZ_TRACKID" => false,
+            _ => true
+        })
+        .cloned()
+        .collect();
+
+    let album = first_track_comments.iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case("ALBUM"))
+        .map(|(_, value)| value.clone());
+    if let Some(album) = album {
+        comments.push((String::from("TITLE"), album));
+    }
+
+    comments
+}
+
+/// Parses track and disc numbers, which may be given as e.g. "3/12".
+fn parse_number(value: &str) -> Option<u32> {
+    value.split("/").next()?.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::album::{Album, AlbumTrack, album_comments, parse_number};
+    use crate::tags::Chapter;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_chapters() {
+        let track = |title: &str, samples: u64| AlbumTrack {
+            flac_path: PathBuf::from(format!("/music/{}.flac", title)),
+            title: String::from(title),
+            samples
+        };
+        let album = Album {
+            tracks: vec![track("a", 66150), track("b", 44100)],
+            sample_rate: 44100
+        };
+
+        let expected = vec![
+            Chapter { title: String::from("a"), start: 0, end: 1500 },
+            Chapter { title: String::from("b"), start: 1500, end: 2500 },
+        ];
+        assert_eq!(expected, album.chapters());
+        assert_eq!(110250, album.total_samples());
+    }
+
+    #[test]
+    fn test_album_comments() {
+        let first_track_comments = vec![
+            (String::from("ALBUM"), String::from("test_album")),
+            (String::from("ARTIST"), String::from("test_artist")),
+            (String::from("TITLE"), String::from("test_title")),
+            (String::from("tracknumber"), String::from("1")),
+        ];
+
+        let expected = vec![
+            (String::from("ALBUM"), String::from("test_album")),
+            (String::from("ARTIST"), String::from("test_artist")),
+            (String::from("TITLE"), String::from("test_album")),
+        ];
+        assert_eq!(expected, album_comments(&first_track_comments));
+    }
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(Some(3), parse_number("3/12"));
+        assert_eq!(Some(7), parse_number(" 07 "));
+        assert_eq!(None, parse_number("A1"));
+    }
+}
@@ -0,0 +1,452 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+pub const CUE: &'static str = "cue";
+// Cue sheet positions are given in CD frames
+const FRAMES_PER_SECOND: u64 = 75;
+// FLAC metadata block type of an embedded cue sheet
+const CUESHEET_BLOCK_TYPE: u8 = 5;
+// Sizes of the fixed parts of a CUESHEET block
+const CUESHEET_HEADER_SIZE: usize = 396;
+const CUESHEET_TRACK_SIZE: usize = 36;
+const CUESHEET_INDEX_SIZE: usize = 12;
+// Lead-out track numbers of CD-DA and other cue sheets
+const LEAD_OUT_CD: u8 = 170;
+const LEAD_OUT: u8 = 255;
+
+/// A track of a cue sheet. Positions are in samples (per channel) from the start of the file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CueTrack {
+    pub number: u32,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    /// First sample of the track (INDEX 01)
+    pub start: u64,
+    /// First sample after the track, None if the track runs to the end of the file
+    pub end: Option<u64>
+}
+
+/// A cue sheet describing the tracks of a single file album rip.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CueSheet {
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub tracks: Vec<CueTrack>
+}
+
+impl CueSheet {
+
+    /// Name of the virtual MP3 presenting a track, e.g. "01 - Title.mp3".
+    pub fn track_file_name(&self, index: usize) -> String {
+        let track = &self.tracks[index];
+        let title = match track.title {
+            Some(ref title) if !title.is_empty() => title.clone(),
+            _ => format!("Track {:02}", track.number)
+        };
+
+        format!("{:02} - {}.mp3", track.number, title.replace("/", "_").replace("\0", ""))
+    }
+
+    /// Builds the vorbis comments of a track from those of the whole album file. Per-track comments
+    /// are replaced by the cue sheet values, album comments are kept and filled in from the cue
+    /// sheet where missing.
+    pub fn track_comments(&self, index: usize, file_comments: &[(String, String)]) -> Vec<(String, String)> {
+        let track = &self.tracks[index];
+        let has_comment = |name: &str| file_comments.iter().any(|(key, _)| key.eq_ignore_ascii_case(name));
+
+        let mut comments: Vec<(String, String)> = file_comments.iter()
+            .filter(|(key, _)| match key.to_uppercase().as_ref() {
+                "TITLE" | "TRACKNUMBER" | "TRACKTOTAL" | "CUESHEET" => false,
+                "ARTIST" => track.performer.is_none(),
+                key => !key.starts_with("CUE_TRACK")
+            })
+            .cloned()
+            .collect();
+
+        if let Some(ref title) = track.title {
+            comments.push((String::from("TITLE"), title.clone()));
+        }
+        if let Some(ref performer) = track.performer {
+            comments.push((String::from("ARTIST"), performer.clone()));
+        } else if let (false, Some(performer)) = (has_comment("ARTIST"), self.performer.as_ref()) {
+            comments.push((String::from("ARTIST"), performer.clone()));
+        }
+        comments.push((String::from("TRACKNUMBER"), track.number.to_string()));
+        comments.push((String::from("TRACKTOTAL"), self.tracks.len().to_string()));
+
+        if let (false, Some(title)) = (has_comment("ALBUM"), self.title.as_ref()) {
+            comments.push((String::from("ALBUM"), title.clone()));
+        }
+        if let (false, Some(performer)) = (has_comment("ALBUMARTIST"), self.performer.as_ref()) {
+            comments.push((String::from("ALBUMARTIST"), performer.clone()));
+        }
+
+        comments
+    }
+
+    /// Fills in track titles and performers from `CUE_TRACKnn_TITLE` and `CUE_TRACKnn_PERFORMER`
+    /// vorbis comments, as embedded cue sheets can't hold them.
+    pub fn add_track_comments(&mut self, comments: &[(String, String)]) {
+        for track in self.tracks.iter_mut() {
+            let title_key = format!("CUE_TRACK{:02}_TITLE", track.number);
+            let performer_key = format!("CUE_TRACK{:02}_PERFORMER", track.number);
+
+            for (key, value) in comments {
+                if key.eq_ignore_ascii_case(&title_key) {
+                    track.title = Some(value.clone());
+                } else if key.eq_ignore_ascii_case(&performer_key) {
+                    track.performer = Some(value.clone());
+                }
+            }
+        }
+    }
+}
+
+/// Returns the path of the .cue sidecar belonging to the provided audio file.
+pub fn sidecar_path(audio_path: &Path) -> PathBuf {
+    audio_path.with_extension(CUE)
+}
+
+/// Reads and parses the .cue sidecar next to the provided audio file, if there is one.
+pub fn read_sidecar(audio_path: &Path, sample_rate: u32) -> Option<CueSheet> {
+    let contents = fs::read(sidecar_path(audio_path)).ok()?;
+
+    // Cue sheets written on Windows are often not UTF-8, fall back to Latin-1
+    let contents = match String::from_utf8(contents) {
+        Ok(contents) => contents,
+        Err(err) => err.into_bytes().iter().map(|byte| *byte as char).collect()
+    };
+
+    parse(&contents, sample_rate)
+}
+
+/// Reads the CUESHEET metadata block embedded in a FLAC, if there is one. Tracks have no titles or
+/// performers, see [`CueSheet::add_track_comments()`].
+pub fn read_embedded(flac_path: &Path) -> Option<CueSheet> {
+    match read_cuesheet_block(flac_path) {
+        Ok(Some(block)) => parse_cuesheet_block(&block),
+        Ok(None) => None,
+        Err(err) => {
+            debug!("Unable to read metadata of {:?}: {}", flac_path, err);
+            None
+        }
+    }
+}
+
+/// Walks the metadata blocks of a FLAC looking for a CUESHEET block. claxon skips over the block
+/// without exposing its contents, so it's read here directly.
+fn read_cuesheet_block(flac_path: &Path) -> io::Result<Option<Vec<u8>>> {
+    // TODO read the block from FlacReader once the claxon fork parses CUESHEET blocks
+    let mut file = File::open(flac_path)?;
+
+    let mut marker = [0u8; 4];
+    file.read_exact(&mut marker)?;
+    if &marker != b"fLaC" {
+        return Ok(None);
+    }
+
+    loop {
+        let mut header = [0u8; 4];
+        file.read_exact(&mut header)?;
+        let is_last = header[0] & 0x80 != 0;
+        let block_type = header[0] & 0x7F;
+        let length = u32::from_be_bytes([0, header[1], header[2], header[3]]);
+
+        if block_type == CUESHEET_BLOCK_TYPE {
+            let mut block = vec![0u8; length as usize];
+            file.read_exact(&mut block)?;
+            return Ok(Some(block));
+        }
+        if is_last {
+            return Ok(None);
+        }
+        file.seek(SeekFrom::Current(i64::from(length)))?;
+    }
+}
+
+/// Parses the contents of a CUESHEET metadata block. Track offsets are already in samples, the end
+/// of the last track is given by the lead-out track.
+fn parse_cuesheet_block(block: &[u8]) -> Option<CueSheet> {
+    let track_count = *block.get(CUESHEET_HEADER_SIZE - 1)? as usize;
+    let mut sheet = CueSheet {
+        title: None,
+        performer: None,
+        tracks: Vec::new()
+    };
+    let mut lead_out: Option<u64> = None;
+
+    let mut position = CUESHEET_HEADER_SIZE;
+    for _ in 0..track_count {
+        let track = block.get(position..position + CUESHEET_TRACK_SIZE)?;
+        let offset = read_u64(&track[0..8]);
+        let number = track[8];
+        let index_count = track[35] as usize;
+        position += CUESHEET_TRACK_SIZE;
+
+        // Tracks start at index point 1, index 0 being the pregap
+        let mut start: Option<u64> = None;
+        for _ in 0..index_count {
+            let index = block.get(position..position + CUESHEET_INDEX_SIZE)?;
+            if index[8] == 1 || start.is_none() {
+                start = Some(offset + read_u64(&index[0..8]));
+            }
+            position += CUESHEET_INDEX_SIZE;
+        }
+
+        if number == LEAD_OUT_CD || number == LEAD_OUT {
+            lead_out = Some(offset);
+            continue;
+        }
+        sheet.tracks.push(CueTrack {
+            number: u32::from(number),
+            title: None,
+            performer: None,
+            start: start.unwrap_or(offset),
+            end: None
+        });
+    }
+
+    // A single track is just the whole file, so there is nothing to split
+    if sheet.tracks.len() < 2 {
+        return None;
+    }
+
+    for index in 1..sheet.tracks.len() {
+        let start = sheet.tracks[index].start;
+        sheet.tracks[index - 1].end = Some(start);
+    }
+    if let Some(last_track) = sheet.tracks.last_mut() {
+        last_track.end = lead_out;
+    }
+
+    Some(sheet)
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut value = [0u8; 8];
+    value.copy_from_slice(&bytes[..8]);
+    u64::from_be_bytes(value)
+}
+
+/// Parses a cue sheet referencing a single file. Returns None for sheets spanning several files or
+/// with fewer than two tracks.
+pub fn parse(contents: &str, sample_rate: u32) -> Option<CueSheet> {
+    let mut sheet = CueSheet {
+        title: None,
+        performer: None,
+        tracks: Vec::new()
+    };
+    let mut files = 0;
+
+    for line in contents.trim_start_matches('\u{feff}').lines() {
+        let line = line.trim();
+        let (command, arguments) = match line.find(char::is_whitespace) {
+            Some(index) => (&line[..index], line[index..].trim()),
+            None => (line, "")
+        };
+
+        match command.to_uppercase().as_ref() {
+            "FILE" => files += 1,
+            "TRACK" => {
+                let number = arguments.split_whitespace().next()?.parse().ok()?;
+                sheet.tracks.push(CueTrack {
+                    number,
+                    title: None,
+                    performer: None,
+                    start: 0,
+                    end: None
+                });
+            },
+            "TITLE" => match sheet.tracks.last_mut() {
+                Some(track) => track.title = Some(unquote(arguments)),
+                None => sheet.title = Some(unquote(arguments))
+            },
+            "PERFORMER" => match sheet.tracks.last_mut() {
+                Some(track) => track.performer = Some(unquote(arguments)),
+                None => sheet.performer = Some(unquote(arguments))
+            },
+            "INDEX" => {
+                let mut arguments = arguments.split_whitespace();
+                if arguments.next() == Some("01") {
+                    let start = parse_position(arguments.next()?)? * u64::from(sample_rate) / FRAMES_PER_SECOND;
+                    sheet.tracks.last_mut()?.start = start;
+                }
+            },
+            _ => ()
+        }
+    }
+
+    if files > 1 || sheet.tracks.len() < 2 {
+        return None;
+    }
+
+    // Each track ends where the next one starts
+    for index in 1..sheet.tracks.len() {
+        let start = sheet.tracks[index].start;
+        sheet.tracks[index - 1].end = Some(start);
+    }
+
+    Some(sheet)
+}
+
+/// Parses a `mm:ss:ff` position into CD frames.
+fn parse_position(position: &str) -> Option<u64> {
+    let parts: Vec<u64> = position.split(":")
+        .map(|part| part.parse().ok())
+        .collect::<Option<Vec<u64>>>()?;
+    match parts.as_slice() {
+        [minutes, seconds, frames] => Some((minutes * 60 + seconds) * FRAMES_PER_SECOND + frames),
+        _ => None
+    }
+}
+
+fn unquote(value: &str) -> String {
+    let value = value.trim();
+    if value.len() >= 2 && value.starts_with("\"") && value.ends_with("\"") {
+        String::from(&value[1..value.len() - 1])
+    } else {
+        String::from(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cue::{
+        CUESHEET_HEADER_SIZE, CUESHEET_INDEX_SIZE, CUESHEET_TRACK_SIZE, CueSheet, CueTrack, parse,
+        parse_cuesheet_block
+    };
+
+    const CUE_SHEET: &'static str = "\
+        REM GENRE Electronic\n\
+        PERFORMER \"test_album_artist\"\n\
+        TITLE \"test_album\"\n\
+        FILE \"album.flac\" WAVE\n\
+        \x20 TRACK 01 AUDIO\n\
+        \x20   TITLE \"First\"\n\
+        \x20   INDEX 01 00:00:00\n\
+        \x20 TRACK 02 AUDIO\n\
+        \x20   TITLE \"Second/Third\"\n\
+        \x20   PERFORMER \"guest_artist\"\n\
+        \x20   INDEX 00 03:59:70\n\
+        \x20   INDEX 01 04:00:15\n";
+
+    #[test]
+    fn test_parse() {
+        let expected = CueSheet {
+            title: Some(String::from("test_album")),
+            performer: Some(String::from("test_album_artist")),
+            tracks: vec![
+                CueTrack {
+                    number: 1,
+                    title: Some(String::from("First")),
+                    performer: None,
+                    start: 0,
+                    end: Some(10592820)
+                },
+                CueTrack {
+                    number: 2,
+                    title: Some(String::from("Second/Third")),
+                    performer: Some(String::from("guest_artist")),
+                    start: 10592820,
+                    end: None
+                },
+            ]
+        };
+        assert_eq!(Some(expected), parse(CUE_SHEET, 44100));
+
+        assert_eq!(None, parse("FILE \"a.flac\" WAVE\nFILE \"b.flac\" WAVE\nTRACK 01 AUDIO\n", 44100));
+        assert_eq!(None, parse("FILE \"a.flac\" WAVE\n", 44100));
+        assert_eq!(None, parse("FILE \"a.flac\" WAVE\nTRACK 01 AUDIO\nINDEX 01 00:00:00\n", 44100));
+    }
+
+    #[test]
+    fn test_track_file_name() {
+        let sheet = parse(CUE_SHEET, 44100).unwrap();
+        assert_eq!("01 - First.mp3", sheet.track_file_name(0));
+        assert_eq!("02 - Second_Third.mp3", sheet.track_file_name(1));
+    }
+
+    #[test]
+    fn test_track_comments() {
+        let sheet = parse(CUE_SHEET, 44100).unwrap();
+        let file_comments = vec![
+            (String::from("ALBUM"), String::from("flac_album")),
+            (String::from("ARTIST"), String::from("flac_artist")),
+            (String::from("TITLE"), String::from("flac_title")),
+            (String::from("DATE"), String::from("2019")),
+        ];
+
+        let expected = vec![
+            (String::from("ALBUM"), String::from("flac_album")),
+            (String::from("ARTIST"), String::from("flac_artist")),
+            (String::from("DATE"), String::from("2019")),
+            (String::from("TITLE"), String::from("First")),
+            (String::from("TRACKNUMBER"), String::from("1")),
+            (String::from("TRACKTOTAL"), String::from("2")),
+            (String::from("ALBUMARTIST"), String::from("test_album_artist")),
+        ];
+        assert_eq!(expected, sheet.track_comments(0, &file_comments));
+
+        let expected = vec![
+            (String::from("ALBUM"), String::from("flac_album")),
+            (String::from("DATE"), String::from("2019")),
+            (String::from("TITLE"), String::from("Second/Third")),
+            (String::from("ARTIST"), String::from("guest_artist")),
+            (String::from("TRACKNUMBER"), String::from("2")),
+            (String::from("TRACKTOTAL"), String::from("2")),
+            (String::from("ALBUMARTIST"), String::from("test_album_artist")),
+        ];
+        assert_eq!(expected, sheet.track_comments(1, &file_comments));
+    }
+
+    fn cuesheet_track(offset: u64, number: u8, indexes: &[(u64, u8)]) -> Vec<u8> {
+        let mut track = vec![0u8; CUESHEET_TRACK_SIZE];
+        track[0..8].copy_from_slice(&offset.to_be_bytes());
+        track[8] = number;
+        track[35] = indexes.len() as u8;
+        for (index_offset, index_number) in indexes {
+            let mut index = vec![0u8; CUESHEET_INDEX_SIZE];
+            index[0..8].copy_from_slice(&index_offset.to_be_bytes());
+            index[8] = *index_number;
+            track.extend(index);
+        }
+        track
+    }
+
+    #[test]
+    fn test_parse_cuesheet_block() {
+        let mut block = vec![0u8; CUESHEET_HEADER_SIZE];
+        block[CUESHEET_HEADER_SIZE - 1] = 3;
+        block.extend(cuesheet_track(0, 1, &[(0, 1)]));
+        // Second track has a 1 second pregap
+        block.extend(cuesheet_track(10584000, 2, &[(0, 0), (44100, 1)]));
+        block.extend(cuesheet_track(20000000, 170, &[]));
+
+        let mut sheet = parse_cuesheet_block(&block).unwrap();
+        assert_eq!(2, sheet.tracks.len());
+        assert_eq!(0, sheet.tracks[0].start);
+        assert_eq!(Some(10628100), sheet.tracks[0].end);
+        assert_eq!(10628100, sheet.tracks[1].start);
+        assert_eq!(Some(20000000), sheet.tracks[1].end);
+        assert_eq!("02 - Track 02.mp3", sheet.track_file_name(1));
+
+        sheet.add_track_comments(&[
+            (String::from("CUE_TRACK02_TITLE"), String::from("Second")),
+            (String::from("cue_track01_performer"), String::from("guest_artist")),
+        ]);
+        assert_eq!(Some(String::from("Second")), sheet.tracks[1].title);
+        assert_eq!(Some(String::from("guest_artist")), sheet.tracks[0].performer);
+        assert_eq!("02 - Second.mp3", sheet.track_file_name(1));
+
+        // Truncated blocks are rejected
+        assert_eq!(None, parse_cuesheet_block(&block[..CUESHEET_HEADER_SIZE + 20]));
+
+        // Single track sheets are not split
+        let mut block = vec![0u8; CUESHEET_HEADER_SIZE];
+        block[CUESHEET_HEADER_SIZE - 1] = 2;
+        block.extend(cuesheet_track(0, 1, &[(0, 1)]));
+        block.extend(cuesheet_track(20000000, 170, &[]));
+        assert_eq!(None, parse_cuesheet_block(&block));
+    }
+}
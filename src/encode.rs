@@ -4,13 +4,20 @@ use std::io;
 use claxon::input::BufferedReader;
 use std::collections::VecDeque;
 use crate::tags;
-use id3::{Tag, Version};
+use id3::{Frame, Tag, Version};
 use std::io::Cursor;
 use std::borrow::{BorrowMut, Borrow};
 use std::cmp::min;
 use std::sync::{Arc, Mutex};
-use claxon::metadata::{StreamInfo, Tags};
-use crate::lame::Lame;
+use crate::album::{self, Album};
+use crate::cue::CueSheet;
+use crate::lame::{info_frame, Lame};
+use crate::loudness::{self, LoudnessMeter};
+use crate::lrc;
+use crate::options::Options;
+use crate::replaygain::{self, ReplayGain};
+use crate::tags::TextPlaceholder;
+use std::path::Path;
 use lame_sys::vbr_mode::vbr_mtrh;
 
 // From LAME
@@ -71,10 +78,32 @@ struct LameWrapper {
 unsafe impl Send for LameWrapper {}
 unsafe impl Sync for LameWrapper {}
 
+/// Loudness measured while encoding files that have no ReplayGain tags.
+struct LoudnessAnalysis {
+    meter: LoudnessMeter,
+    // Locations of the reserved ReplayGain values within the tag
+    gain_placeholder: Option<TextPlaceholder>,
+    peak_placeholder: Option<TextPlaceholder>
+}
+
 pub struct FlacToMp3Encoder<R: io::Read> {
     lame_wrapper: LameWrapper,
     flac_samples: FlacSamples<BufferedReader<R>>,
-    stream_info: StreamInfo,
+    // FLACs encoded after the current one, for whole-album files
+    queued_readers: VecDeque<FlacReader<R>>,
+    // Interleaved samples to discard before the encoded range starts
+    samples_to_skip: u64,
+    // Interleaved samples left to encode, None if encoding runs to the end of the stream
+    samples_remaining: Option<u64>,
+    // Samples (per channel) in the encoded range, if known
+    total_samples: Option<u64>,
+    // ReplayGain values written to the LAME info frame
+    replay_gain: ReplayGain,
+    // Factor applied to every sample before encoding
+    sample_scale: f64,
+    loudness_analysis: Option<LoudnessAnalysis>,
+    // Location of the reserved iTunSMPB value within the tag
+    itunes_smpb_placeholder: Option<TextPlaceholder>,
     // Size (in bytes) of tags
     tag_size: usize,
     encoding_finished: bool,
@@ -84,14 +113,95 @@ pub struct FlacToMp3Encoder<R: io::Read> {
 /// Encoder for a FLAC file.
 impl FlacToMp3Encoder<File> {
 
-    pub fn new(flac_reader: FlacReader<File>) -> FlacToMp3Encoder<File> {
+    /// Creates an encoder for the provided FLAC. `stored_replay_gain` holds previously computed
+    /// values, used wherever the FLAC has no ReplayGain tags of its own.
+    pub fn new(
+        flac_reader: FlacReader<File>, source_path: &Path, stored_replay_gain: Option<ReplayGain>, options: &Options
+    ) -> FlacToMp3Encoder<File> {
+        let comments = vorbis_comments(&flac_reader);
+        let mut replay_gain = ReplayGain::from_vorbis_comments(
+            comments.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+        );
+        if let Some(stored_replay_gain) = stored_replay_gain {
+            replay_gain = replay_gain.or(&stored_replay_gain);
+        }
+        let lyrics = lrc::read_sidecar(source_path);
+
+        FlacToMp3Encoder::with_range(flac_reader, comments, lyrics, replay_gain, &[], 0, None, options)
+    }
+
+    /// Creates an encoder for a single track of a FLAC holding a whole album, as described by the
+    /// track at `index` of its cue sheet.
+    pub fn new_cue_track(
+        flac_reader: FlacReader<File>, cue_sheet: &CueSheet, index: usize, stored_replay_gain: Option<ReplayGain>,
+        options: &Options
+    ) -> FlacToMp3Encoder<File> {
+        let file_comments = vorbis_comments(&flac_reader);
+        let comments = cue_sheet.track_comments(index, &file_comments);
+
+        // Gain measured over the whole file is the album gain of every track
+        let mut file_replay_gain = ReplayGain::from_vorbis_comments(
+            file_comments.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+        );
+        if let Some(stored_replay_gain) = stored_replay_gain {
+            file_replay_gain = file_replay_gain.or(&stored_replay_gain);
+        }
+        let replay_gain = ReplayGain {
+            track_gain: None,
+            track_peak: None,
+            album_gain: file_replay_gain.album_gain.or(file_replay_gain.track_gain),
+            album_peak: file_replay_gain.album_peak.or(file_replay_gain.track_peak)
+        };
+
+        let track = &cue_sheet.tracks[index];
+        FlacToMp3Encoder::with_range(flac_reader, comments, None, replay_gain, &[], track.start, track.end, options)
+    }
+
+    /// Creates an encoder joining the tracks of an album into one gapless MP3, with a chapter per
+    /// track. `flac_readers` must be opened from the tracks of `album`, in order. Returns None if
+    /// there are none.
+    pub fn new_album(flac_readers: Vec<FlacReader<File>>, album: &Album, options: &Options) -> Option<FlacToMp3Encoder<File>> {
+        let mut flac_readers: VecDeque<FlacReader<File>> = flac_readers.into_iter().collect();
+        let flac_reader = flac_readers.pop_front()?;
+
+        let first_track_comments = vorbis_comments(&flac_reader);
+        let comments = album::album_comments(&first_track_comments);
+
+        // The whole file is one album, so the album gain is its track gain as well
+        let album_replay_gain = ReplayGain::from_vorbis_comments(
+            first_track_comments.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+        );
+        let replay_gain = ReplayGain {
+            track_gain: album_replay_gain.album_gain,
+            track_peak: album_replay_gain.album_peak,
+            album_gain: album_replay_gain.album_gain,
+            album_peak: album_replay_gain.album_peak
+        };
+
+        let mut encoder = FlacToMp3Encoder::with_range(
+            flac_reader, comments, None, replay_gain, &album.chapters(), 0, Some(album.total_samples()), options
+        );
+        encoder.queued_readers = flac_readers;
+        Some(encoder)
+    }
+
+    /// Creates an encoder for the samples (per channel) from `start` up to `end` of the FLAC, or up
+    /// to the end of the stream if `end` is None.
+    fn with_range(
+        flac_reader: FlacReader<File>, comments: Vec<(String, String)>, lyrics: Option<Vec<lrc::LyricLine>>,
+        replay_gain: ReplayGain, chapters: &[tags::Chapter], start: u64, end: Option<u64>, options: &Options
+    ) -> FlacToMp3Encoder<File> {
         // 8MB
         let mut output_buffer = VecDeque::with_capacity(8388608);
-        // Initialize tags
-        let flac_tags = flac_reader.tags();
-        let tag_size = FlacToMp3Encoder::initialize_tags(flac_tags, &mut output_buffer);
-
+        let sample_scale = replay_gain.scale_factor(options.replaygain);
+        let replay_gain = if options.replaygain_tags { replay_gain } else { ReplayGain::default() };
         let stream_info = flac_reader.streaminfo();
+        let total_samples = match end {
+            Some(end) => Some(end.saturating_sub(start)),
+            None => stream_info.samples.map(|samples| samples.saturating_sub(start))
+        };
+        let channels = u64::from(stream_info.channels);
+
         // Initialize LAME
         let mut lame = Lame::new().expect("Failed to initialize LAME context");
         lame.set_channels(stream_info.channels).expect("Failed to call lame.set_channels()");
@@ -100,40 +210,194 @@ impl FlacToMp3Encoder<File> {
         lame.set_vbr_quality(0).expect("Failed to call lame.set_vbr_quality()");
         lame.set_vbr_max_bitrate(320).expect("Failed to call lame.set_vbr_max_bitrate()");
         lame.set_write_vbr_tag(true).expect("Failed to call lame.set_write_vbr_tag()");
+        if let Some(samples) = total_samples {
+            lame.set_num_samples(samples).expect("Failed to call lame.set_num_samples()");
+        }
         lame.init_params().expect("Failed to call lame.init_params()");
 
+        let encoder_settings = format!("LAME {} -V{} vbr_mtrh", Lame::get_version(), lame.get_vbr_quality());
+        let transcode_frames = tags::transcode_frames(
+            &encoder_settings, stream_info.bits_per_sample, stream_info.sample_rate, &stream_info.md5sum
+        );
+
+        let analyze_loudness = options.analyze_loudness && options.replaygain_tags && replay_gain.track_gain.is_none();
+        let write_itunes_smpb = options.itunsmpb && total_samples.is_some();
+        let tag_bytes = FlacToMp3Encoder::initialize_tags(
+            comments, lyrics, transcode_frames, chapters, &replay_gain, analyze_loudness, write_itunes_smpb, options
+        );
+        let loudness_analysis = if analyze_loudness {
+            Some(LoudnessAnalysis {
+                meter: LoudnessMeter::new(stream_info.channels, stream_info.sample_rate),
+                gain_placeholder: tags::find_text_placeholder(
+                    &tag_bytes, &replaygain::TRACK_GAIN.to_lowercase(), replaygain::GAIN_PLACEHOLDER
+                ),
+                peak_placeholder: tags::find_text_placeholder(
+                    &tag_bytes, &replaygain::TRACK_PEAK.to_lowercase(), replaygain::PEAK_PLACEHOLDER
+                )
+            })
+        } else {
+            None
+        };
+        let itunes_smpb_placeholder = if write_itunes_smpb {
+            tags::find_text_placeholder(&tag_bytes, tags::ITUNSMPB, &tags::itunes_smpb(0, 0, 0, 0))
+        } else {
+            None
+        };
+        let tag_size = tag_bytes.len();
+        output_buffer.extend(tag_bytes);
+
         FlacToMp3Encoder {
             flac_samples: flac_reader.samples_owned(),
+            queued_readers: VecDeque::new(),
             lame_wrapper: LameWrapper {
                 lame: Arc::from(Mutex::new(lame))
             },
-            stream_info,
+            samples_to_skip: start * channels,
+            samples_remaining: end.map(|end| end.saturating_sub(start) * channels),
+            total_samples,
+            replay_gain,
+            sample_scale,
+            loudness_analysis,
+            itunes_smpb_placeholder,
             tag_size,
             encoding_finished: false,
             output_buffer
         }
     }
 
-    /// Injects tag data into the output stream, which should happen before encoding starts.
-    fn initialize_tags(flac_tags: Tags, output_buffer: &mut VecDeque<u8>) -> usize {
+    /// Builds the tag data, which is injected into the output stream before encoding starts.
+    /// If `reserve_loudness` or `reserve_itunes_smpb` are set, placeholder values are written so the
+    /// measured loudness and gapless information can be patched in once encoding finishes.
+    fn initialize_tags(
+        comments: Vec<(String, String)>, lyrics: Option<Vec<lrc::LyricLine>>, transcode_frames: Vec<Frame>,
+        chapters: &[tags::Chapter], replay_gain: &ReplayGain, reserve_loudness: bool, reserve_itunes_smpb: bool,
+        options: &Options
+    ) -> Vec<u8> {
         let mut tag_buffer: Cursor<Vec<u8>> = Cursor::new(Vec::with_capacity(2048));
         let mut mp3_tag = Tag::new();
 
-        for tag in flac_tags {
-            match tags::translate_vorbis_comment_to_id3(
-                &String::from(tag.0), &String::from(tag.1)
-            ) {
-                Some(frame) => mp3_tag.add_frame(frame),
-                None => None
+        for (name, value) in comments {
+            // ReplayGain values are translated below, as they may not come from the FLAC
+            if replaygain::is_replaygain_comment(&name) {
+                continue;
+            }
+
+            match options.tag_mapping.translate(&name, &value, &options.comment_language) {
+                Some(frame) => tags::add_frame(&mut mp3_tag, frame),
+                None => ()
             };
         }
 
-        mp3_tag.write_to(tag_buffer.borrow_mut(), Version::Id3v23).expect("Failed to write tags");
+        // Lyrics from an .lrc sidecar are embedded as SYLT, plus USLT unless the FLAC already had lyrics
+        if let Some(lines) = lyrics {
+            mp3_tag.add_frame(tags::synchronised_lyrics_frame(&lines, &options.comment_language));
+            if mp3_tag.get("USLT").is_none() {
+                mp3_tag.add_frame(tags::unsynchronised_lyrics_frame(&lines, &options.comment_language));
+            }
+        }
+
+        for frame in transcode_frames {
+            tags::add_frame(&mut mp3_tag, frame);
+        }
+
+        // foobar2000 style TXXX frames unless the tag mapping says otherwise. replay_gain is empty if
+        // ReplayGain tags are disabled.
+        let replay_gain_values = vec![
+            (replaygain::TRACK_GAIN, replay_gain.track_gain.map(replaygain::format_gain)),
+            (replaygain::TRACK_PEAK, replay_gain.track_peak.map(replaygain::format_peak)),
+            (replaygain::ALBUM_GAIN, replay_gain.album_gain.map(replaygain::format_gain)),
+            (replaygain::ALBUM_PEAK, replay_gain.album_peak.map(replaygain::format_peak)),
+        ];
+        for (name, value) in replay_gain_values {
+            let frame = value.and_then(|value| {
+                options.tag_mapping.translate(&String::from(name), &value, &options.comment_language)
+            });
+            if let Some(frame) = frame {
+                tags::add_frame(&mut mp3_tag, frame);
+            }
+        }
+
+        // Measured loudness is only patched into the default frames, so remapped values are left out
+        if reserve_loudness {
+            if !options.tag_mapping.is_overridden(replaygain::TRACK_GAIN) {
+                mp3_tag.add_extended_text(replaygain::TRACK_GAIN.to_lowercase(), replaygain::GAIN_PLACEHOLDER);
+            }
+            if !options.tag_mapping.is_overridden(replaygain::TRACK_PEAK) {
+                mp3_tag.add_extended_text(replaygain::TRACK_PEAK.to_lowercase(), replaygain::PEAK_PLACEHOLDER);
+            }
+        }
+
+        if reserve_itunes_smpb {
+            tags::add_frame(&mut mp3_tag, tags::comment_frame(tags::ITUNSMPB, &tags::itunes_smpb(0, 0, 0, 0), "eng"));
+        }
+
+        // RVA2 only exists in ID3v2.4
+        let version = if options.rva2 { Version::Id3v24 } else { Version::Id3v23 };
+        mp3_tag.write_to(tag_buffer.borrow_mut(), version).expect("Failed to write tags");
+
+        if options.rva2 {
+            let mut rva2_frames: Vec<u8> = Vec::new();
+            if let Some(gain) = replay_gain.track_gain {
+                let body = tags::relative_volume_adjustment_body("track", gain, replay_gain.track_peak);
+                rva2_frames.extend(tags::encode_raw_frame("RVA2", &body, version));
+            }
+            if let Some(gain) = replay_gain.album_gain {
+                let body = tags::relative_volume_adjustment_body("album", gain, replay_gain.album_peak);
+                rva2_frames.extend(tags::encode_raw_frame("RVA2", &body, version));
+            }
+            tags::insert_raw_frames(tag_buffer.get_mut(), &rva2_frames);
+        }
+
+        if !chapters.is_empty() {
+            tags::insert_raw_frames(tag_buffer.get_mut(), &tags::chapter_frames(chapters, version));
+        }
+
+        tag_buffer.into_inner()
+    }
+
+    /// Writes the loudness measured during encoding into the reserved tag values and the ReplayGain
+    /// fields of the LAME info frame.
+    fn write_measured_loudness(&mut self, analysis: LoudnessAnalysis) {
+        let peak = analysis.meter.peak();
+        self.replay_gain.track_peak = Some(peak);
+        if let Some(placeholder) = analysis.peak_placeholder {
+            self.patch_output(&placeholder, &replaygain::format_peak_fixed_width(peak));
+        }
+
+        // Silent tracks have no meaningful loudness, leave the gain at 0
+        let gain = match analysis.meter.integrated_loudness() {
+            Some(integrated_loudness) => loudness::replaygain(integrated_loudness),
+            None => return
+        };
+        self.replay_gain.track_gain = Some(gain);
+        if let Some(placeholder) = analysis.gain_placeholder {
+            self.patch_output(&placeholder, &replaygain::format_gain_fixed_width(gain));
+        }
+    }
 
-        for byte in tag_buffer.get_ref() {
-            output_buffer.push_back(byte.clone());
+    /// Returns the next interleaved sample, moving on to the next queued FLAC at the end of each stream.
+    fn next_sample(&mut self) -> Option<i32> {
+        loop {
+            match self.flac_samples.next() {
+                Some(sample) => return Some(sample.unwrap()),
+                None => match self.queued_readers.pop_front() {
+                    Some(flac_reader) => self.flac_samples = flac_reader.samples_owned(),
+                    None => return None
+                }
+            }
+        }
+    }
+
+    /// Overwrites a placeholder value in the tag at the front of the output buffer.
+    fn patch_output(&mut self, placeholder: &TextPlaceholder, value: &str) {
+        match placeholder.encode(value) {
+            Some(bytes) => {
+                for (index, byte) in bytes.into_iter().enumerate() {
+                    self.output_buffer[placeholder.offset + index] = byte;
+                }
+            },
+            None => warn!("Unable to write {} into tag placeholder", value)
         }
-        tag_buffer.get_ref().len()
     }
 }
 
@@ -141,26 +405,44 @@ impl FlacToMp3Encoder<File> {
 impl Encode<File> for FlacToMp3Encoder<File> {
 
     fn encode(&mut self, size: usize) -> usize {
+        // FLAC streams can't be seeked, so samples before the range are decoded and dropped
+        while self.samples_to_skip > 0 {
+            match self.flac_samples.next() {
+                Some(_) => self.samples_to_skip -= 1,
+                None => self.samples_to_skip = 0
+            }
+        }
+
         //TODO can this memory be recycled?
         let mut pcm_left: Vec<i16> = Vec::with_capacity(size);
         let mut pcm_right: Vec<i16> = Vec::with_capacity(size);
 
         for _ in 0..size*2 {
-            match self.flac_samples.next() {
-                // TODO support 24-bit FLAC
-                Some(l_frame) => pcm_left.push(l_frame.unwrap() as i16),
+            if self.samples_remaining == Some(0) {
+                break;
+            }
+            // TODO support 24-bit FLAC
+            let l_sample = match self.next_sample() {
+                Some(l_frame) => l_frame,
                 None => {
                     break;
                 }
             };
-
-            match self.flac_samples.next() {
-                // TODO support 24-bit FLAC
-                Some(r_frame) => pcm_right.push(r_frame.unwrap() as i16),
+            let r_sample = match self.next_sample() {
+                Some(r_frame) => r_frame,
                 None => {
                     break;
                 }
             };
+
+            if let Some(analysis) = self.loudness_analysis.as_mut() {
+                analysis.meter.add_frame(&[l_sample, r_sample]);
+            }
+            pcm_left.push(replaygain::apply_scale(l_sample, self.sample_scale));
+            pcm_right.push(replaygain::apply_scale(r_sample, self.sample_scale));
+            if let Some(remaining) = self.samples_remaining.as_mut() {
+                *remaining = remaining.saturating_sub(2);
+            }
         }
 
         let sample_count = pcm_right.len();
@@ -183,6 +465,10 @@ impl Encode<File> for FlacToMp3Encoder<File> {
     }
 
     fn encode_finalize(&mut self) -> usize {
+        if let Some(analysis) = self.loudness_analysis.take() {
+            self.write_measured_loudness(analysis);
+        }
+
         // Collect remaining output of internal LAME buffers once we reach the end
         // of the PCM data stream
         let mut lame_buffer = vec![0; 7200];
@@ -200,18 +486,31 @@ impl Encode<File> for FlacToMp3Encoder<File> {
         let mut vbr_buffer = vec![0; MAX_VBR_FRAME_SIZE];
         let vbr_frame_length = lame.get_vbr_tag(&mut vbr_buffer);
         vbr_buffer.truncate(vbr_frame_length);
+        info_frame::set_replay_gain(&mut vbr_buffer, &self.replay_gain);
+        info_frame::set_delay_and_padding(&mut vbr_buffer, lame.get_encoder_delay(), lame.get_encoder_padding());
         let mut index = 0;
         for byte in vbr_buffer {
             std::mem::replace(&mut self.output_buffer[self.tag_size + index], byte);
             index += 1;
         }
+
+        let itunes_smpb = match self.total_samples {
+            Some(samples) => tags::itunes_smpb(
+                lame.get_encoder_delay(), lame.get_framesize(), lame.get_frame_num(), samples
+            ),
+            None => String::new()
+        };
+        drop(lame);
+        if let Some(placeholder) = self.itunes_smpb_placeholder.take() {
+            self.patch_output(&placeholder, &itunes_smpb);
+        }
         self.encoding_finished = true;
 
         flush_output_length
     }
 
     fn calculate_size(&mut self) -> u64 {
-        let sample_count = self.stream_info.samples.expect("Unable to get PCM sample count");
+        let sample_count = self.total_samples.expect("Unable to get PCM sample count");
         let mut lame = self.lame_wrapper.lame.lock().unwrap();
         let bitrate = lame.get_vbr_max_bitrate();
         let samplerate = lame.get_out_samplerate();
@@ -232,3 +531,10 @@ impl Encode<File> for FlacToMp3Encoder<File> {
         return self.encoding_finished;
     }
 }
+
+/// Collects the vorbis comments of a FLAC.
+pub fn vorbis_comments<R: io::Read>(flac_reader: &FlacReader<R>) -> Vec<(String, String)> {
+    flac_reader.tags()
+        .map(|(name, value)| (String::from(name), String::from(value)))
+        .collect()
+}
@@ -1,107 +1,172 @@
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
 
 pub type Inode = u64;
 
+/// Inode of the mountpoint.
+pub const ROOT_INODE: Inode = 1;
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
 /// Contains data associated with an inode
 struct InodeTableEntry {
     inode: Inode,
-    lookups: u64
+    lookups: u64,
+    // Salt the hash of the path was taken with to get a free number
+    salt: u64
 }
 
 pub struct InodeTable {
     inodes_by_path: HashMap<PathBuf, InodeTableEntry>,
     paths_by_inode: HashMap<Inode, PathBuf>,
-    // TODO recycle inodes
-    next_inode: Inode
+    // Files in the target directory backing each inode, so operations on an inode needn't map its
+    // path back to the source
+    sources_by_inode: HashMap<Inode, PathBuf>,
+    hash: fn(&Path, u64) -> Inode
 }
 
 impl InodeTable {
     pub fn new() -> InodeTable {
-        let mut next_inode = 1;
+        InodeTable::with_hash(path_inode)
+    }
 
+    /// Creates an inode table numbering paths with the provided salted hash function.
+    fn with_hash(hash: fn(&Path, u64) -> Inode) -> InodeTable {
         let mut inodes_by_path = HashMap::new();
         inodes_by_path.insert(PathBuf::from("/"), InodeTableEntry {
-            inode: next_inode,
+            inode: ROOT_INODE,
             lookups: 1,
+            salt: 0
         });
 
         let mut paths_by_inode = HashMap::new();
-        paths_by_inode.insert(next_inode, PathBuf::from("/"));
-
-        next_inode += 1;
+        paths_by_inode.insert(ROOT_INODE, PathBuf::from("/"));
 
         InodeTable {
             inodes_by_path,
             paths_by_inode,
-            next_inode
+            sources_by_inode: HashMap::new(),
+            hash
         }
     }
 
-    /// Increments the lookup count of the provided inode. Returns the updated lookup count.
-    pub fn lookup(&mut self, inode: Inode) -> u64 {
-        let path = match self.paths_by_inode.get(&inode) {
-            Some(path) => path,
-            None => panic!("Attempted lookup on an unknown inode")
-        };
-        let mut inode_entry = match self.inodes_by_path.get_mut(path) {
-            Some(inode_entry) => inode_entry,
-            None => panic!("Attempted lookup on an unknown path")
-        };
+    /// Increments the lookup count of the provided inode, as the kernel does for every entry
+    /// replied to a lookup. Returns the updated lookup count, or None if the inode is unknown.
+    pub fn lookup(&mut self, inode: Inode) -> Option<u64> {
+        let path = self.paths_by_inode.get(&inode)?;
+        let inode_entry = self.inodes_by_path.get_mut(path)?;
         inode_entry.lookups += 1;
-        inode_entry.lookups
+        Some(inode_entry.lookups)
     }
 
     /// Returns the inode number and path assigned to the provided parent_ino/name combination.
-    /// If the inode is not in the inode_table it will be added with a lookup count of 0.
-    pub fn add_or_get(&mut self, parent_inode: Inode, name: &OsStr) -> (Inode, PathBuf) {
-        let parent_path = match self.paths_by_inode.get(&parent_inode) {
-            Some(path) => path,
-            None => panic!("Attempted lookup on an unknown parent_inode")
-        };
+    /// If the inode is not in the inode_table it will be added with a lookup count of 0. Returns
+    /// None if the parent inode is unknown.
+    pub fn add_or_get(&mut self, parent_inode: Inode, name: &OsStr) -> Option<(Inode, PathBuf)> {
+        let path = self.paths_by_inode.get(&parent_inode)?.join(name);
+        Some(self.add_or_get_path(&path))
+    }
 
-        let path: PathBuf = [parent_path, &PathBuf::from(name)].iter().collect();
-        match self.inodes_by_path.get_mut(&path) {
+    /// Returns the inode number assigned to the provided path, adding it with a lookup count of 0
+    /// if it's not in the inode_table.
+    /// Several paths under the mountpoint may be backed by the same source, e.g. tracks within the
+    /// virtual views. These resolve to the inode of a single canonical path, so they appear as hard
+    /// links to one file and share its lookup count.
+    pub fn add_or_get_path(&mut self, path: &Path) -> (Inode, PathBuf) {
+        match self.inodes_by_path.get_mut(path) {
             Some(inode) => {
-                (inode.inode, path.clone())
+                (inode.inode, path.to_path_buf())
             },
             None => {
-                let inode = self.next_inode;
-                self.inodes_by_path.insert(path.clone(), InodeTableEntry {
+                let (inode, salt) = self.free_inode(path, 0);
+                self.inodes_by_path.insert(path.to_path_buf(), InodeTableEntry {
                     inode,
-                    lookups: 0
+                    lookups: 0,
+                    salt
                 });
-                self.paths_by_inode.insert(inode, path.clone());
-
-                self.next_inode += 1;
-                (inode, path.clone())
+                self.paths_by_inode.insert(inode, path.to_path_buf());
+                (inode, path.to_path_buf())
             }
         }
     }
 
-    /// Forgets the provided inode.
-    pub fn forget(&mut self, ino: Inode, nlookups: u64) {
-        // inode 1 is special and cannot be forgotten
-        if ino == 1 {
-            return;
+    /// Returns a free number for a path being added, along with the salt its hash was taken with,
+    /// starting at `salt`. Numbers only depend on the path, so they are the same across remounts.
+    /// In the unlikely case of a collision the path sorting first keeps the number and the other
+    /// one is hashed again with the next salt, so colliding paths are numbered the same whatever
+    /// order they're added in. Paths looked up by the kernel keep their number regardless.
+    fn free_inode(&mut self, path: &Path, mut salt: u64) -> (Inode, u64) {
+        loop {
+            let inode = (self.hash)(path, salt);
+            if inode > ROOT_INODE {
+                let other_path = match self.paths_by_inode.get(&inode) {
+                    Some(other_path) => other_path.clone(),
+                    None => return (inode, salt)
+                };
+                if path < other_path.as_path() && self.inodes_by_path[&other_path].lookups == 0 {
+                    self.renumber(&other_path);
+                    continue;
+                }
+            }
+            salt += 1;
         }
+    }
 
-        let path = match self.paths_by_inode.get(&ino) {
-            Some(path) => path,
+    /// Moves a path on to the number its hash takes with the next salt, making room for a path
+    /// colliding with it.
+    fn renumber(&mut self, path: &Path) {
+        let (old_inode, old_salt) = match self.inodes_by_path.get(path) {
+            Some(entry) => (entry.inode, entry.salt),
             None => return
         };
+        self.paths_by_inode.remove(&old_inode);
+        let source = self.sources_by_inode.remove(&old_inode);
 
-        let inode_entry = match self.inodes_by_path.get_mut(path) {
-            Some(inode_entry) => inode_entry,
-            None => return
-        };
+        let (inode, salt) = self.free_inode(path, old_salt + 1);
+        if let Some(entry) = self.inodes_by_path.get_mut(path) {
+            entry.inode = inode;
+            entry.salt = salt;
+        }
+        self.paths_by_inode.insert(inode, path.to_path_buf());
+        if let Some(source) = source {
+            self.sources_by_inode.insert(inode, source);
+        }
+    }
+
+    /// Forgets lookups of the provided inode, removing it once none are left. Lookup counts don't go
+    /// below 0, as inodes listed by readdir were never counted by the kernel. Removed inodes get the
+    /// same number when their path is added again. Returns the updated lookup count, or None if the
+    /// inode is unknown.
+    pub fn forget(&mut self, ino: Inode, nlookups: u64) -> Option<u64> {
+        let path = self.paths_by_inode.get(&ino)?.clone();
+        let inode_entry = self.inodes_by_path.get_mut(&path)?;
+
+        // inode 1 is special and cannot be forgotten
+        if ino == ROOT_INODE {
+            return Some(inode_entry.lookups);
+        }
 
-        inode_entry.lookups -= nlookups;
-        if inode_entry.lookups <= 0 {
-            self.inodes_by_path.remove(path);
+        inode_entry.lookups = inode_entry.lookups.saturating_sub(nlookups);
+        let lookups = inode_entry.lookups;
+        if lookups == 0 {
+            self.inodes_by_path.remove(&path);
             self.paths_by_inode.remove(&ino);
+            self.sources_by_inode.remove(&ino);
         }
+        Some(lookups)
+    }
+
+    /// Records the file in the target directory backing the provided inode.
+    pub fn set_source(&mut self, inode: Inode, source: &Path) {
+        self.sources_by_inode.insert(inode, source.to_path_buf());
+    }
+
+    /// Gets the file in the target directory backing the provided inode, if it has one. Virtual
+    /// files and directories don't.
+    pub fn get_source(&self, inode: Inode) -> Option<&PathBuf> {
+        self.sources_by_inode.get(&inode)
     }
 
     /// Gets the path of the provided inode number.
@@ -117,4 +182,113 @@ impl InodeTable {
             None => None
         }
     }
-}
\ No newline at end of file
+}
+
+/// Derives the inode number of a path under the mountpoint from a 64 bit FNV-1a hash of it,
+/// followed by the salt unless it's 0.
+fn path_inode(path: &Path, salt: u64) -> Inode {
+    let salt_bytes = salt.to_le_bytes();
+    let salt_bytes: &[u8] = if salt == 0 { &[] } else { &salt_bytes };
+    path.as_os_str().as_bytes().iter().chain(salt_bytes).fold(FNV_OFFSET_BASIS, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(FNV_PRIME)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::inode::{Inode, InodeTable, ROOT_INODE, path_inode};
+    use std::ffi::OsStr;
+    use std::path::Path;
+
+    #[test]
+    fn test_stable_inodes() {
+        let mut inode_table = InodeTable::new();
+        let (album, _path) = inode_table.add_or_get(ROOT_INODE, OsStr::new("album")).unwrap();
+        let (track, path) = inode_table.add_or_get(album, OsStr::new("01 - a.mp3")).unwrap();
+        assert_eq!(Path::new("/album/01 - a.mp3"), path);
+
+        // Numbers don't depend on the order paths are added in
+        let mut other_inode_table = InodeTable::new();
+        assert_eq!(track, other_inode_table.add_or_get_path(Path::new("/album/01 - a.mp3")).0);
+        assert_eq!(album, other_inode_table.add_or_get_path(Path::new("/album")).0);
+        assert_eq!(path_inode(Path::new("/album"), 0), album);
+        assert_eq!(Some(ROOT_INODE), inode_table.get_inode(&Path::new("/").to_path_buf()));
+    }
+
+    #[test]
+    fn test_lookup_counts() {
+        let mut inode_table = InodeTable::new();
+
+        // Inodes listed by readdir start without lookups, so forgetting them mustn't underflow
+        let (inode, path) = inode_table.add_or_get_path(Path::new("/a.mp3"));
+        inode_table.set_source(inode, Path::new("/music/a.flac"));
+        assert_eq!(Some(0), inode_table.forget(inode, 1));
+        assert_eq!(None, inode_table.get_path(inode));
+
+        assert_eq!(inode, inode_table.add_or_get_path(&path).0);
+        assert_eq!(Some(1), inode_table.lookup(inode));
+        assert_eq!(Some(2), inode_table.lookup(inode));
+        assert_eq!(Some(1), inode_table.forget(inode, 1));
+        assert_eq!(Some(0), inode_table.forget(inode, 5));
+
+        // Forgotten inodes are removed, and numbered the same when added again
+        assert_eq!(None, inode_table.get_path(inode));
+        assert_eq!(None, inode_table.get_inode(&path));
+        assert_eq!(None, inode_table.get_source(inode));
+        assert_eq!(inode, inode_table.add_or_get_path(&path).0);
+
+        // The root is never forgotten
+        assert_eq!(Some(1), inode_table.forget(ROOT_INODE, 10));
+    }
+
+    #[test]
+    fn test_unknown_inodes() {
+        let mut inode_table = InodeTable::new();
+        let unknown = 12345;
+
+        assert_eq!(None, inode_table.lookup(unknown));
+        assert_eq!(None, inode_table.forget(unknown, 1));
+        assert_eq!(None, inode_table.add_or_get(unknown, OsStr::new("a.mp3")));
+        assert_eq!(None, inode_table.get_path(unknown));
+        assert_eq!(None, inode_table.get_source(unknown));
+    }
+
+    #[test]
+    fn test_path_inode() {
+        // Reference values of 64 bit FNV-1a
+        assert_eq!(0xcbf29ce484222325, path_inode(Path::new(""), 0));
+        assert_eq!(0xaf63dc4c8601ec8c, path_inode(Path::new("a"), 0));
+        assert_ne!(path_inode(Path::new("a"), 0), path_inode(Path::new("a"), 1));
+    }
+
+    #[test]
+    fn test_colliding_inodes() {
+        // Every path hashes to the same number unless salted
+        fn colliding_inode(path: &Path, salt: u64) -> Inode {
+            if salt == 0 { 100 } else { path_inode(path, salt) }
+        }
+        let (a, b) = (Path::new("/a.mp3"), Path::new("/b.mp3"));
+
+        let mut inode_table = InodeTable::with_hash(colliding_inode);
+        let (a_inode, _path) = inode_table.add_or_get_path(a);
+        let (b_inode, _path) = inode_table.add_or_get_path(b);
+        assert_eq!(100, a_inode);
+        assert_eq!(path_inode(b, 1), b_inode);
+
+        // Colliding paths get the same numbers in either order, and keep their source
+        let mut other_inode_table = InodeTable::with_hash(colliding_inode);
+        assert_eq!(100, other_inode_table.add_or_get_path(b).0);
+        other_inode_table.set_source(100, Path::new("/music/b.flac"));
+        assert_eq!(a_inode, other_inode_table.add_or_get_path(a).0);
+        assert_eq!(Some(b_inode), other_inode_table.get_inode(&b.to_path_buf()));
+        assert_eq!(Some(&b.to_path_buf()), other_inode_table.get_path(b_inode));
+        assert_eq!(Some(&Path::new("/music/b.flac").to_path_buf()), other_inode_table.get_source(b_inode));
+
+        // Numbers the kernel looked up aren't moved
+        let mut held_inode_table = InodeTable::with_hash(colliding_inode);
+        held_inode_table.add_or_get_path(b);
+        held_inode_table.lookup(100);
+        assert_eq!(path_inode(a, 1), held_inode_table.add_or_get_path(a).0);
+        assert_eq!(Some(100), held_inode_table.get_inode(&b.to_path_buf()));
+    }
+}
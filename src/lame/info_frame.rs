@@ -0,0 +1,158 @@
+use crate::replaygain::ReplayGain;
+
+// Offset of the LAME extension from the start of the Xing/Info header
+const LAME_EXTENSION_OFFSET: usize = 120;
+// Offsets of the fields within the LAME extension
+const PEAK_OFFSET: usize = 11;
+const RADIO_GAIN_OFFSET: usize = 15;
+const AUDIOPHILE_GAIN_OFFSET: usize = 17;
+const DELAY_PADDING_OFFSET: usize = 21;
+const CRC_OFFSET: usize = 34;
+// Encoder delay and padding are stored in 12 bits each
+const MAX_DELAY_PADDING: u32 = 0xFFF;
+// ReplayGain name codes
+const NAME_RADIO: u16 = 1;
+const NAME_AUDIOPHILE: u16 = 2;
+// ReplayGain originator code for values computed by a ReplayGain model
+const ORIGINATOR_MODEL: u16 = 3;
+
+/// Finds the start of the LAME extension within an info frame returned by `lame_get_lametag_frame`.
+fn find_lame_extension(frame: &[u8]) -> Option<usize> {
+    let header_offset = frame.windows(4)
+        .position(|window| window == b"Xing" || window == b"Info")?;
+    let lame_offset = header_offset + LAME_EXTENSION_OFFSET;
+
+    if frame.len() < lame_offset + CRC_OFFSET + 2 || &frame[lame_offset..lame_offset + 4] != b"LAME" {
+        return None;
+    }
+    Some(lame_offset)
+}
+
+/// Fills in the ReplayGain fields of a LAME info frame. Track values go in the radio field and
+/// album values in the audiophile field. Returns false if the frame has no LAME extension.
+pub fn set_replay_gain(frame: &mut [u8], replay_gain: &ReplayGain) -> bool {
+    let lame_offset = match find_lame_extension(frame) {
+        Some(lame_offset) => lame_offset,
+        None => return false
+    };
+
+    if let Some(peak) = replay_gain.track_peak {
+        // Peak amplitude is stored as a fixed point number with 23 fractional bits
+        let peak = (peak * 8388608.0).round().max(0.0).min(u32::max_value() as f64) as u32;
+        frame[lame_offset + PEAK_OFFSET..lame_offset + PEAK_OFFSET + 4].copy_from_slice(&peak.to_be_bytes());
+    }
+    if let Some(gain) = replay_gain.track_gain {
+        let field = encode_gain_field(NAME_RADIO, gain);
+        frame[lame_offset + RADIO_GAIN_OFFSET..lame_offset + RADIO_GAIN_OFFSET + 2].copy_from_slice(&field.to_be_bytes());
+    }
+    if let Some(gain) = replay_gain.album_gain {
+        let field = encode_gain_field(NAME_AUDIOPHILE, gain);
+        frame[lame_offset + AUDIOPHILE_GAIN_OFFSET..lame_offset + AUDIOPHILE_GAIN_OFFSET + 2].copy_from_slice(&field.to_be_bytes());
+    }
+
+    update_crc(frame, lame_offset);
+    true
+}
+
+/// Sets the encoder delay and padding (in samples) used by gapless players. Returns false if the
+/// frame has no LAME extension.
+pub fn set_delay_and_padding(frame: &mut [u8], delay: u32, padding: u32) -> bool {
+    let lame_offset = match find_lame_extension(frame) {
+        Some(lame_offset) => lame_offset,
+        None => return false
+    };
+
+    let delay = delay.min(MAX_DELAY_PADDING);
+    let padding = padding.min(MAX_DELAY_PADDING);
+    let offset = lame_offset + DELAY_PADDING_OFFSET;
+    frame[offset] = (delay >> 4) as u8;
+    frame[offset + 1] = (((delay & 0xF) << 4) | (padding >> 8)) as u8;
+    frame[offset + 2] = (padding & 0xFF) as u8;
+
+    update_crc(frame, lame_offset);
+    true
+}
+
+/// Encodes a ReplayGain field: 3 bits name code, 3 bits originator code, a sign bit and the
+/// absolute gain in 0.1 dB steps.
+fn encode_gain_field(name: u16, gain: f64) -> u16 {
+    let magnitude = (gain.abs() * 10.0).round().min(511.0) as u16;
+    let sign = if gain < 0.0 { 1 } else { 0 };
+
+    (name << 13) | (ORIGINATOR_MODEL << 10) | (sign << 9) | magnitude
+}
+
+/// Recomputes the info tag CRC, which covers every byte of the frame preceding it.
+fn update_crc(frame: &mut [u8], lame_offset: usize) {
+    let crc_offset = lame_offset + CRC_OFFSET;
+    let crc = crc16(&frame[..crc_offset]);
+    frame[crc_offset..crc_offset + 2].copy_from_slice(&crc.to_be_bytes());
+}
+
+/// CRC-16 as used by LAME (polynomial 0x8005, reflected, initial value 0).
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in data {
+        crc ^= u16::from(*byte);
+        for _ in 0..8 {
+            if crc & 1 != 0 {
+                crc = (crc >> 1) ^ 0xA001;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lame::info_frame::{crc16, encode_gain_field, set_delay_and_padding, set_replay_gain};
+    use crate::replaygain::ReplayGain;
+
+    #[test]
+    fn test_crc16() {
+        assert_eq!(0xBB3D, crc16(b"123456789"));
+    }
+
+    #[test]
+    fn test_encode_gain_field() {
+        // 001 radio, 011 set by model, 1 negative, 74 * 0.1 dB
+        assert_eq!(0x2E4A, encode_gain_field(1, -7.4));
+        // 010 audiophile, 011 set by model, 0 positive, 31 * 0.1 dB
+        assert_eq!(0x4C1F, encode_gain_field(2, 3.1));
+    }
+
+    #[test]
+    fn test_set_replay_gain() {
+        let mut frame = vec![0u8; 200];
+        frame[36..40].copy_from_slice(b"Info");
+        frame[156..160].copy_from_slice(b"LAME");
+
+        let replay_gain = ReplayGain {
+            track_gain: Some(-7.4),
+            track_peak: Some(0.5),
+            album_gain: Some(3.1),
+            album_peak: None
+        };
+        assert!(set_replay_gain(&mut frame, &replay_gain));
+        assert_eq!(vec![0x00, 0x40, 0x00, 0x00], frame[167..171].to_vec());
+        assert_eq!(vec![0x2E, 0x4A], frame[171..173].to_vec());
+        assert_eq!(vec![0x4C, 0x1F], frame[173..175].to_vec());
+
+        let crc = crc16(&frame[..190]);
+        assert_eq!(crc.to_be_bytes().to_vec(), frame[190..192].to_vec());
+
+        assert!(set_delay_and_padding(&mut frame, 576, 1234));
+        // 0x240 and 0x4D2 packed into 24 bits
+        assert_eq!(vec![0x24, 0x04, 0xD2], frame[177..180].to_vec());
+        let crc = crc16(&frame[..190]);
+        assert_eq!(crc.to_be_bytes().to_vec(), frame[190..192].to_vec());
+
+        // Frames without a LAME extension are left alone
+        let mut frame = vec![0u8; 200];
+        assert!(!set_replay_gain(&mut frame, &replay_gain));
+        assert!(!set_delay_and_padding(&mut frame, 576, 1234));
+        assert_eq!(vec![0u8; 200], frame);
+    }
+}
@@ -1,6 +1,9 @@
+pub mod info_frame;
+
 use lame_sys::{lame_global_flags, vbr_mode};
+use std::ffi::CStr;
 use std::ptr;
-use std::os::raw::c_int;
+use std::os::raw::{c_int, c_ulong};
 
 pub struct Lame {
     context: *mut lame_global_flags
@@ -21,6 +24,13 @@ impl Lame {
         }
     }
 
+    /// Returns the LAME version, e.g. "3.100".
+    pub fn get_version() -> String {
+        unsafe {
+            CStr::from_ptr(lame_sys::get_lame_short_version()).to_string_lossy().into_owned()
+        }
+    }
+
     pub fn get_channels(&mut self) -> u32 {
         unsafe {
             lame_sys::lame_get_num_channels(self.context) as u32
@@ -111,6 +121,36 @@ impl Lame {
         })
     }
 
+    pub fn set_num_samples(&mut self, num_samples: u64) -> Result<(), Error> {
+        handle_return_code(unsafe {
+            lame_sys::lame_set_num_samples(self.context, num_samples as c_ulong)
+        })
+    }
+
+    pub fn get_encoder_delay(&mut self) -> u32 {
+        unsafe {
+            lame_sys::lame_get_encoder_delay(self.context) as u32
+        }
+    }
+
+    pub fn get_encoder_padding(&mut self) -> u32 {
+        unsafe {
+            lame_sys::lame_get_encoder_padding(self.context) as u32
+        }
+    }
+
+    pub fn get_framesize(&mut self) -> u32 {
+        unsafe {
+            lame_sys::lame_get_framesize(self.context) as u32
+        }
+    }
+
+    pub fn get_frame_num(&mut self) -> u32 {
+        unsafe {
+            lame_sys::lame_get_frameNum(self.context) as u32
+        }
+    }
+
     pub fn init_params(&mut self) -> Result<(), Error> {
         handle_return_code(unsafe {
             lame_sys::lame_init_params(self.context)
@@ -4,26 +4,39 @@ extern crate libc;
 extern crate log;
 extern crate simplelog;
 
+pub mod album;
+pub mod cue;
 pub mod encode;
 pub mod lame;
+pub mod loudness;
+pub mod loudness_db;
+pub mod lrc;
 pub mod mp3v0fs;
+pub mod options;
+pub mod replaygain;
+pub mod sanitize;
 pub mod tags;
+pub mod template;
+pub mod views;
 pub mod inode;
 
 use crate::mp3v0fs::Mp3V0Fs;
+use crate::options::Options;
 
 use std::ffi::{OsString, OsStr};
 use std::io::Result;
 use fuse::BackgroundSession;
 
-pub fn run(target: &OsString, mountpoint: &OsString, fuse_args: &Vec<&OsStr>) -> Result<()> {
-    let filesystem = Mp3V0Fs::new(target.clone());
+pub fn run(target: &OsString, mountpoint: &OsString, options: &Options, fuse_args: &Vec<&OsStr>) -> Result<()> {
+    let filesystem = Mp3V0Fs::new(target.clone(), options.clone());
 
     fuse::mount(filesystem, mountpoint, fuse_args)
 }
 
-pub fn run_async<'a>(target: &OsString, mountpoint: &OsString, fuse_args: &Vec<&OsStr>) -> Result<BackgroundSession<'a>> {
-    let filesystem = Mp3V0Fs::new(target.clone());
+pub fn run_async<'a>(
+    target: &OsString, mountpoint: &OsString, options: &Options, fuse_args: &Vec<&OsStr>
+) -> Result<BackgroundSession<'a>> {
+    let filesystem = Mp3V0Fs::new(target.clone(), options.clone());
 
     unsafe {
         fuse::spawn_mount(
@@ -0,0 +1,212 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+/// ReplayGain 2.0 reference loudness in LUFS.
+pub const REFERENCE_LOUDNESS: f64 = -18.0;
+
+// Gating blocks are 400ms long and overlap by 75%, so they're built from 100ms sub-blocks
+const SUB_BLOCKS_PER_BLOCK: usize = 4;
+const ABSOLUTE_GATE: f64 = -70.0;
+const RELATIVE_GATE: f64 = -10.0;
+// Full scale of the 16-bit samples fed to the meter
+const FULL_SCALE: f64 = 32768.0;
+
+/// Second order IIR filter in transposed direct form II.
+#[derive(Clone)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+    z: [f64; 2]
+}
+
+impl Biquad {
+    fn process(&mut self, input: f64) -> f64 {
+        let output = self.b[0] * input + self.z[0];
+        self.z[0] = self.b[1] * input - self.a[1] * output + self.z[1];
+        self.z[1] = self.b[2] * input - self.a[2] * output;
+        output
+    }
+}
+
+/// Builds the two stage K-weighting filter from ITU-R BS.1770 for an arbitrary sample rate.
+/// Coefficients are derived the same way as in libebur128.
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let rate = f64::from(sample_rate);
+
+    // Stage 1: high shelf modelling the acoustic effect of the head
+    let f0 = 1681.974450955533;
+    let gain = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+        a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2]
+    };
+
+    // Stage 2: RLB high pass
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2]
+    };
+
+    [shelf, high_pass]
+}
+
+/// Measures the integrated loudness (EBU R128) and sample peak of a PCM stream.
+pub struct LoudnessMeter {
+    filters: Vec<[Biquad; 2]>,
+    sub_block_size: usize,
+    // Summed channel energy of the sub-block being filled
+    sub_block_energy: f64,
+    sub_block_frames: usize,
+    // Mean energies of the most recent complete sub-blocks
+    sub_blocks: VecDeque<f64>,
+    // Mean energy of every 400ms gating block seen so far
+    block_energies: Vec<f64>,
+    peak: f64
+}
+
+impl LoudnessMeter {
+
+    pub fn new(channels: u32, sample_rate: u32) -> LoudnessMeter {
+        LoudnessMeter {
+            filters: (0..channels).map(|_| k_weighting(sample_rate)).collect(),
+            sub_block_size: (sample_rate / 10) as usize,
+            sub_block_energy: 0.0,
+            sub_block_frames: 0,
+            sub_blocks: VecDeque::with_capacity(SUB_BLOCKS_PER_BLOCK),
+            block_energies: Vec::new(),
+            peak: 0.0
+        }
+    }
+
+    /// Adds one frame of 16-bit samples, one per channel.
+    pub fn add_frame(&mut self, samples: &[i32]) {
+        for (filters, sample) in self.filters.iter_mut().zip(samples) {
+            let sample = f64::from(*sample) / FULL_SCALE;
+            if sample.abs() > self.peak {
+                self.peak = sample.abs();
+            }
+
+            let shelved = filters[0].process(sample);
+            let filtered = filters[1].process(shelved);
+            self.sub_block_energy += filtered * filtered;
+        }
+
+        self.sub_block_frames += 1;
+        if self.sub_block_frames == self.sub_block_size {
+            self.finish_sub_block();
+        }
+    }
+
+    fn finish_sub_block(&mut self) {
+        if self.sub_blocks.len() == SUB_BLOCKS_PER_BLOCK {
+            self.sub_blocks.pop_front();
+        }
+        self.sub_blocks.push_back(self.sub_block_energy / self.sub_block_frames as f64);
+        self.sub_block_energy = 0.0;
+        self.sub_block_frames = 0;
+
+        if self.sub_blocks.len() == SUB_BLOCKS_PER_BLOCK {
+            let block_energy = self.sub_blocks.iter().sum::<f64>() / SUB_BLOCKS_PER_BLOCK as f64;
+            self.block_energies.push(block_energy);
+        }
+    }
+
+    /// Integrated loudness in LUFS, or None if the stream was too short or silent.
+    pub fn integrated_loudness(&self) -> Option<f64> {
+        gated_loudness(&self.block_energies)
+    }
+
+    /// Sample peak relative to full scale.
+    pub fn peak(&self) -> f64 {
+        self.peak
+    }
+
+    /// Energies of the gating blocks, which can be combined across tracks to measure an album.
+    pub fn block_energies(&self) -> &[f64] {
+        &self.block_energies
+    }
+}
+
+/// Computes the gated loudness (in LUFS) of a set of gating block energies.
+pub fn gated_loudness(block_energies: &[f64]) -> Option<f64> {
+    let absolute_gate = energy(ABSOLUTE_GATE);
+    let above_absolute: Vec<f64> = block_energies.iter()
+        .cloned()
+        .filter(|block_energy| *block_energy > absolute_gate)
+        .collect();
+    if above_absolute.is_empty() {
+        return None;
+    }
+
+    let relative_gate = energy(loudness(mean(&above_absolute)) + RELATIVE_GATE);
+    let above_relative: Vec<f64> = above_absolute.into_iter()
+        .filter(|block_energy| *block_energy > relative_gate)
+        .collect();
+    if above_relative.is_empty() {
+        return None;
+    }
+
+    Some(loudness(mean(&above_relative)))
+}
+
+/// ReplayGain 2.0 gain (in dB) bringing a track of the given loudness to the reference level.
+pub fn replaygain(loudness: f64) -> f64 {
+    REFERENCE_LOUDNESS - loudness
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+fn loudness(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+fn energy(loudness: f64) -> f64 {
+    10f64.powf((loudness + 0.691) / 10.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::loudness::LoudnessMeter;
+    use std::f64::consts::PI;
+
+    #[test]
+    fn test_sine_loudness() {
+        // A 1kHz stereo sine at -23 dBFS measures -23 LUFS (EBU Tech 3341, test case 1)
+        let sample_rate = 48000;
+        let amplitude = 10f64.powf(-23.0 / 20.0) * 32768.0;
+        let mut meter = LoudnessMeter::new(2, sample_rate);
+
+        for n in 0..sample_rate * 5 {
+            let sample = (amplitude * (2.0 * PI * 1000.0 * f64::from(n) / f64::from(sample_rate)).sin()) as i32;
+            meter.add_frame(&[sample, sample]);
+        }
+
+        let loudness = meter.integrated_loudness().unwrap();
+        assert!((loudness - -23.0).abs() < 0.1, "unexpected loudness {}", loudness);
+        assert!((meter.peak() - 0.0708).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_silence() {
+        let mut meter = LoudnessMeter::new(2, 44100);
+        for _ in 0..44100 {
+            meter.add_frame(&[0, 0]);
+        }
+
+        assert_eq!(None, meter.integrated_loudness());
+        assert_eq!(0.0, meter.peak());
+    }
+}
@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::{self, read_dir};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use claxon::FlacReader;
+use crate::loudness::{self, LoudnessMeter};
+use crate::mp3v0fs::{FLAC, has_extension};
+use crate::replaygain::ReplayGain;
+
+// Marker for a missing value in the database file
+const NONE: &'static str = "-";
+// How often a running scan writes its results to disk
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Loudness results of a single source file.
+struct Entry {
+    mtime: i64,
+    replay_gain: ReplayGain
+}
+
+/// On-disk store of ReplayGain values computed by [`spawn_scan()`], keyed by source path and
+/// modification time so edited files are rescanned.
+///
+/// The file holds one line per source file with tab separated mtime, track gain, track peak,
+/// album gain, album peak and path fields.
+pub struct LoudnessDb {
+    path: PathBuf,
+    entries: HashMap<PathBuf, Entry>,
+    // Whether entries were inserted since the file was last written
+    unsaved: bool
+}
+
+impl LoudnessDb {
+
+    /// Opens the database at the provided path. A missing or unreadable file results in an empty
+    /// database.
+    pub fn open(path: &Path) -> LoudnessDb {
+        let entries = match fs::read(path) {
+            Ok(contents) => parse(&contents),
+            Err(err) => {
+                info!("Starting new loudness database at {:?}: {}", path, err);
+                HashMap::new()
+            }
+        };
+
+        LoudnessDb {
+            path: path.to_path_buf(),
+            entries,
+            unsaved: false
+        }
+    }
+
+    /// Returns the stored values for a source file, unless it was modified since it was scanned.
+    pub fn get(&self, source_path: &Path, mtime: i64) -> Option<&ReplayGain> {
+        match self.entries.get(source_path) {
+            Some(entry) if entry.mtime == mtime => Some(&entry.replay_gain),
+            _ => None
+        }
+    }
+
+    pub fn insert(&mut self, source_path: PathBuf, mtime: i64, replay_gain: ReplayGain) {
+        self.entries.insert(source_path, Entry { mtime, replay_gain });
+        self.unsaved = true;
+    }
+
+    /// Writes the database to disk, replacing the previous file atomically.
+    pub fn save(&mut self) -> io::Result<()> {
+        let temporary_path = self.path.with_extension("tmp");
+        fs::write(&temporary_path, serialize(&self.entries))?;
+        fs::rename(temporary_path, &self.path)?;
+        self.unsaved = false;
+        Ok(())
+    }
+}
+
+fn parse(contents: &[u8]) -> HashMap<PathBuf, Entry> {
+    let mut entries = HashMap::new();
+
+    for line in contents.split(|byte| *byte == b'\n').filter(|line| !line.is_empty()) {
+        let fields: Vec<&[u8]> = line.splitn(6, |byte| *byte == b'\t').collect();
+        if fields.len() != 6 {
+            warn!("Ignoring malformed loudness database line");
+            continue;
+        }
+
+        let mtime = match String::from_utf8_lossy(fields[0]).parse() {
+            Ok(mtime) => mtime,
+            Err(_) => continue
+        };
+        let replay_gain = ReplayGain {
+            track_gain: parse_value(fields[1]),
+            track_peak: parse_value(fields[2]),
+            album_gain: parse_value(fields[3]),
+            album_peak: parse_value(fields[4])
+        };
+        entries.insert(PathBuf::from(OsStr::from_bytes(fields[5])), Entry { mtime, replay_gain });
+    }
+
+    entries
+}
+
+fn parse_value(field: &[u8]) -> Option<f64> {
+    String::from_utf8_lossy(field).parse().ok()
+}
+
+fn serialize(entries: &HashMap<PathBuf, Entry>) -> Vec<u8> {
+    let mut contents: Vec<u8> = Vec::new();
+
+    for (path, entry) in entries {
+        let replay_gain = &entry.replay_gain;
+        let fields = format!(
+            "{}\t{}\t{}\t{}\t{}\t", entry.mtime,
+            format_value(replay_gain.track_gain), format_value(replay_gain.track_peak),
+            format_value(replay_gain.album_gain), format_value(replay_gain.album_peak)
+        );
+        contents.extend_from_slice(fields.as_bytes());
+        contents.extend_from_slice(path.as_os_str().as_bytes());
+        contents.push(b'\n');
+    }
+
+    contents
+}
+
+fn format_value(value: Option<f64>) -> String {
+    match value {
+        Some(value) => format!("{:.6}", value),
+        None => String::from(NONE)
+    }
+}
+
+/// Returns the modification time used to key the database.
+pub fn modification_time(path: &Path) -> Option<i64> {
+    fs::metadata(path).ok().map(|metadata| metadata.mtime())
+}
+
+/// Starts a background job that scans every directory below `root` and stores the track and album
+/// loudness of the FLACs it contains. Each directory is treated as one album. Results are written
+/// to disk every minute and once the scan ends.
+pub fn spawn_scan(root: PathBuf, db: Arc<Mutex<LoudnessDb>>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        info!("Starting loudness scan of {:?}", root);
+        let mut last_save = Instant::now();
+        scan_directory(&root, &db, &mut last_save);
+
+        let mut db = db.lock().unwrap();
+        if db.unsaved {
+            save(&mut db);
+        }
+        info!("Finished loudness scan of {:?}", root);
+    })
+}
+
+fn scan_directory(directory: &Path, db: &Arc<Mutex<LoudnessDb>>, last_save: &mut Instant) {
+    let entries = match read_dir(directory) {
+        Ok(entries) => entries,
+        Err(err) => {
+            warn!("Unable to scan {:?} for loudness: {}", directory, err);
+            return;
+        }
+    };
+
+    let mut flacs: Vec<PathBuf> = Vec::new();
+    let mut subdirectories: Vec<PathBuf> = Vec::new();
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => subdirectories.push(path),
+            Ok(file_type) if file_type.is_file() && has_extension(&path, FLAC) => flacs.push(path),
+            _ => ()
+        }
+    }
+    flacs.sort();
+
+    let up_to_date = {
+        let db = db.lock().unwrap();
+        flacs.iter().all(|flac| match modification_time(flac) {
+            Some(mtime) => db.get(flac, mtime).is_some(),
+            None => true
+        })
+    };
+    if !up_to_date {
+        let results = analyze_album(&flacs);
+        let mut db = db.lock().unwrap();
+        for (path, mtime, replay_gain) in results {
+            db.insert(path, mtime, replay_gain);
+        }
+        if last_save.elapsed() >= SAVE_INTERVAL {
+            save(&mut db);
+            *last_save = Instant::now();
+        }
+    }
+
+    for subdirectory in subdirectories {
+        scan_directory(&subdirectory, db, last_save);
+    }
+}
+
+fn save(db: &mut LoudnessDb) {
+    if let Err(err) = db.save() {
+        warn!("Failed to save loudness database: {}", err);
+    }
+}
+
+/// Measures every track of an album, then combines the gating blocks of all tracks to get the
+/// album loudness.
+fn analyze_album(flacs: &[PathBuf]) -> Vec<(PathBuf, i64, ReplayGain)> {
+    let mut tracks: Vec<(PathBuf, i64, LoudnessMeter)> = Vec::new();
+    for flac in flacs {
+        let mtime = match modification_time(flac) {
+            Some(mtime) => mtime,
+            None => continue
+        };
+        match analyze_track(flac) {
+            Ok(meter) => tracks.push((flac.clone(), mtime, meter)),
+            Err(err) => warn!("Failed to measure loudness of {:?}: {}", flac, err)
+        }
+    }
+
+    let album_blocks: Vec<f64> = tracks.iter()
+        .flat_map(|(_, _, meter)| meter.block_energies().iter().cloned())
+        .collect();
+    let album_gain = loudness::gated_loudness(&album_blocks).map(loudness::replaygain);
+    let album_peak = tracks.iter()
+        .map(|(_, _, meter)| meter.peak())
+        .fold(None, |peak: Option<f64>, track_peak| Some(peak.unwrap_or(0.0).max(track_peak)));
+
+    tracks.into_iter()
+        .map(|(path, mtime, meter)| {
+            let replay_gain = ReplayGain {
+                track_gain: meter.integrated_loudness().map(loudness::replaygain),
+                track_peak: Some(meter.peak()),
+                album_gain,
+                album_peak
+            };
+            (path, mtime, replay_gain)
+        })
+        .collect()
+}
+
+fn analyze_track(path: &Path) -> Result<LoudnessMeter, claxon::Error> {
+    let mut flac_reader = FlacReader::open(path)?;
+    let stream_info = flac_reader.streaminfo();
+    let channels = stream_info.channels as usize;
+    let bits_per_sample = stream_info.bits_per_sample;
+
+    let mut meter = LoudnessMeter::new(stream_info.channels, stream_info.sample_rate);
+    let mut frame: Vec<i32> = Vec::with_capacity(channels);
+    for sample in flac_reader.samples() {
+        frame.push(to_16_bit(sample?, bits_per_sample));
+        if frame.len() == channels {
+            meter.add_frame(&frame);
+            frame.clear();
+        }
+    }
+
+    Ok(meter)
+}
+
+/// Scales a sample of the given bit depth to 16 bits, as expected by the meter.
+fn to_16_bit(sample: i32, bits_per_sample: u32) -> i32 {
+    if bits_per_sample > 16 {
+        sample >> (bits_per_sample - 16)
+    } else {
+        sample << (16 - bits_per_sample)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::loudness_db::{LoudnessDb, parse, serialize, to_16_bit};
+    use crate::replaygain::ReplayGain;
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_serialize_and_parse() {
+        let mut db = LoudnessDb::open(Path::new("/nonexistent/loudness.db"));
+        let replay_gain = ReplayGain {
+            track_gain: Some(-7.45),
+            track_peak: Some(0.987654),
+            album_gain: Some(-6.5),
+            album_peak: None
+        };
+        // Paths don't have to be valid UTF-8
+        let path = PathBuf::from(OsStr::from_bytes(b"/music/caf\xe9\ttab.flac"));
+        db.insert(path.clone(), 1500000000, replay_gain.clone());
+
+        let entries = parse(&serialize(&db.entries));
+        assert_eq!(1, entries.len());
+        assert_eq!(1500000000, entries[&path].mtime);
+        assert_eq!(replay_gain, entries[&path].replay_gain);
+
+        assert_eq!(Some(&replay_gain), db.get(&path, 1500000000));
+        // Modified since the scan
+        assert_eq!(None, db.get(&path, 1500000001));
+        assert_eq!(None, db.get(Path::new("/music/other.flac"), 1500000000));
+    }
+
+    #[test]
+    fn test_to_16_bit() {
+        assert_eq!(0x1234, to_16_bit(0x123456, 24));
+        assert_eq!(-256, to_16_bit(-1, 8));
+        assert_eq!(1000, to_16_bit(1000, 16));
+    }
+}
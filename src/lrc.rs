@@ -0,0 +1,141 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const LRC: &'static str = "lrc";
+
+/// A single line of synchronized lyrics.
+#[derive(Debug, PartialEq)]
+pub struct LyricLine {
+    /// Time (in milliseconds) at which the line starts
+    pub timestamp: u32,
+    pub text: String
+}
+
+/// Returns the path of the .lrc sidecar belonging to the provided audio file.
+pub fn sidecar_path(audio_path: &Path) -> PathBuf {
+    audio_path.with_extension(LRC)
+}
+
+/// Reads and parses the .lrc sidecar next to the provided audio file, if there is one.
+pub fn read_sidecar(audio_path: &Path) -> Option<Vec<LyricLine>> {
+    let contents = match fs::read(sidecar_path(audio_path)) {
+        Ok(contents) => contents,
+        Err(_) => return None
+    };
+
+    let lines = parse(&String::from_utf8_lossy(&contents));
+    match lines.len() {
+        0 => None,
+        _ => Some(lines)
+    }
+}
+
+/// Parses the contents of an .lrc file into lyric lines sorted by timestamp.
+/// Lines with several timestamps (e.g. a repeated chorus) produce one entry per timestamp, ID tags
+/// other than `[offset:]` are ignored.
+pub fn parse(contents: &str) -> Vec<LyricLine> {
+    let mut offset: i64 = 0;
+    let mut lines = Vec::new();
+
+    for line in contents.trim_start_matches('\u{feff}').lines() {
+        let mut remainder = line.trim();
+        let mut timestamps = Vec::new();
+
+        while remainder.starts_with("[") {
+            let end = match remainder.find("]") {
+                Some(end) => end,
+                None => break
+            };
+            let tag = &remainder[1..end];
+            remainder = &remainder[end + 1..];
+
+            if let Some(timestamp) = parse_timestamp(tag) {
+                timestamps.push(timestamp);
+            } else if tag.to_lowercase().starts_with("offset:") {
+                offset = tag[7..].trim().parse().unwrap_or(0);
+            }
+        }
+
+        for timestamp in timestamps {
+            lines.push((timestamp, remainder.trim().to_owned()));
+        }
+    }
+
+    // A positive offset shifts the lyrics earlier
+    let mut lines: Vec<LyricLine> = lines.into_iter()
+        .map(|(timestamp, text)| LyricLine {
+            timestamp: (timestamp - offset).max(0) as u32,
+            text
+        })
+        .collect();
+    lines.sort_by_key(|line| line.timestamp);
+    lines
+}
+
+/// Parses a `mm:ss`, `mm:ss.xx` or `mm:ss.xxx` timestamp into milliseconds.
+fn parse_timestamp(tag: &str) -> Option<i64> {
+    let colon = tag.find(":")?;
+    let minutes: i64 = tag[..colon].parse().ok()?;
+
+    let seconds_and_fraction = &tag[colon + 1..];
+    let (seconds, fraction) = match seconds_and_fraction.find(|c| c == '.' || c == ':') {
+        Some(index) => (&seconds_and_fraction[..index], &seconds_and_fraction[index + 1..]),
+        None => (seconds_and_fraction, "")
+    };
+    let seconds: i64 = seconds.parse().ok()?;
+
+    let milliseconds: i64 = match fraction.len() {
+        0 => 0,
+        1 => fraction.parse::<i64>().ok()? * 100,
+        2 => fraction.parse::<i64>().ok()? * 10,
+        _ => fraction.get(..3)?.parse().ok()?
+    };
+
+    Some((minutes * 60 + seconds) * 1000 + milliseconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lrc::{LyricLine, parse};
+
+    #[test]
+    fn test_parse() {
+        let contents = "[ar:test_artist]\n\
+            [ti:test_title]\n\
+            [00:12.00]First line\n\
+            [00:17.20][01:02.5]Chorus\n\
+            \n\
+            [00:25.123]Third line\n\
+            not a lyric line\n";
+
+        let expected = vec![
+            LyricLine { timestamp: 12000, text: String::from("First line") },
+            LyricLine { timestamp: 17200, text: String::from("Chorus") },
+            LyricLine { timestamp: 25123, text: String::from("Third line") },
+            LyricLine { timestamp: 62500, text: String::from("Chorus") },
+        ];
+        assert_eq!(expected, parse(contents));
+    }
+
+    #[test]
+    fn test_parse_offset() {
+        let contents = "[offset:+500]\n[00:01.00]One\n[00:00.20]Zero";
+
+        let expected = vec![
+            LyricLine { timestamp: 0, text: String::from("Zero") },
+            LyricLine { timestamp: 500, text: String::from("One") },
+        ];
+        assert_eq!(expected, parse(contents));
+    }
+
+    #[test]
+    fn test_parse_malformed_timestamp() {
+        // A multi-byte character where the milliseconds should end mustn't panic
+        let contents = "[00:01.12\u{e9}]Broken\n[00:02.00]Two";
+
+        let expected = vec![
+            LyricLine { timestamp: 2000, text: String::from("Two") },
+        ];
+        assert_eq!(expected, parse(contents));
+    }
+}
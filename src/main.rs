@@ -1,4 +1,5 @@
 use mp3v0fs::run;
+use mp3v0fs::options::Options;
 
 use crossbeam_utils::thread;
 use simplelog::{CombinedLogger, LevelFilter, Config, SimpleLogger};
@@ -21,14 +22,30 @@ fn main() {
 
     let args: Vec<OsString> = env::args_os().collect();
 
-    if args.len() != 3 {
-        println!("usage: {} <target> <mountpoint>", &env::args().next().unwrap());
+    if args.len() != 3 && !(args.len() == 5 && args[3] == "-o") {
+        println!("usage: {} <target> <mountpoint> [-o option[,option]...]", &env::args().next().unwrap());
+        println!("Commas within option values are escaped as \\,");
         exit(1);
     }
 
     let target = args[1].clone();
     let mountpoint = args[2].clone();
 
+    let mut options = Options::default();
+    if args.len() == 5 {
+        let option_string = match args[4].to_str() {
+            Some(option_string) => option_string,
+            None => {
+                println!("options must be valid UTF-8");
+                exit(1);
+            }
+        };
+        if let Err(err) = options.parse(option_string) {
+            println!("{}", err);
+            exit(1);
+        }
+    }
+
     let fuse_args: Vec<&OsStr> = vec![
         &OsStr::new("-o"), &OsStr::new("auto_unmount"),
         &OsStr::new("-o"), &OsStr::new("rdonly")
@@ -36,7 +53,7 @@ fn main() {
 
     match thread::scope(|s| {
         s.spawn(|_| {
-            match run(&target, &mountpoint, &fuse_args) {
+            match run(&target, &mountpoint, &options, &fuse_args) {
                 Ok(()) => (),
                 Err(err) => panic!("Error occurred {}", err)
             }
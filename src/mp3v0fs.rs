@@ -1,71 +1,620 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ffi::{OsStr, OsString, CString};
 use std::fs::{File, read_dir};
-use std::os::unix::ffi::OsStringExt;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::os::unix::fs::MetadataExt;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::vec::Vec;
 
-use crate::encode::{Encode, FlacToMp3Encoder};
+use crate::album::{self, Album};
+use crate::cue::{self, CueSheet};
+use crate::encode::{self, Encode, FlacToMp3Encoder};
 use claxon::FlacReader;
 use std::sync::{Arc, Mutex};
 use fuse::{Filesystem, FileAttr, FileType, ReplyOpen, ReplyAttr, ReplyData, ReplyXattr, ReplyEmpty, Request, ReplyEntry, ReplyDirectory};
-use crate::inode::{InodeTable, Inode};
-use std::time::Duration;
+use crate::inode::{InodeTable, Inode, ROOT_INODE};
+use crate::loudness_db::{self, LoudnessDb};
+use crate::lrc;
+use crate::options::{CollisionPolicy, Options, SymlinkPolicy};
+use crate::replaygain::ReplayGain;
+use crate::sanitize::{self, SanitizeMode};
+use crate::views::{self, Library, LibraryEntry};
+use std::time::{Duration, SystemTime};
 
-const FLAC: &'static str = "flac";
-const MP3: &'static str = "mp3";
+pub(crate) const FLAC: &'static str = "flac";
+pub(crate) const MP3: &'static str = "mp3";
 const TTL: Duration = Duration::from_secs(1);
 
+/// A file under the mountpoint that has no counterpart of its own in the target directory.
+enum VirtualFile {
+    /// A single track of a FLAC holding a whole album, split using its cue sheet
+    CueTrack {
+        flac_path: PathBuf,
+        cue_sheet: Arc<CueSheet>,
+        index: usize,
+        // Samples (per channel) in the whole FLAC
+        total_samples: u64
+    },
+    /// All FLACs of a directory joined into one MP3
+    Album {
+        album: Arc<Album>
+    }
+}
+
 pub struct Mp3V0Fs {
     pub target: OsString,
+    options: Options,
+    loudness_db: Option<Arc<Mutex<LoudnessDb>>>,
+    // Virtual files by their path under the mountpoint
+    virtual_files: HashMap<PathBuf, VirtualFile>,
+    library: Option<Arc<Mutex<Library>>>,
+    // Source paths of the entries listed so far by their path under the mountpoint, and the reverse
+    sources: HashMap<PathBuf, PathBuf>,
+    fuse_paths: HashMap<PathBuf, PathBuf>,
+    // Source paths of the entries listed so far, by directory under the mountpoint and collision
+    // key. Tracks of cue sheet split FLACs have their FLAC as source.
+    names: HashMap<PathBuf, HashMap<OsString, PathBuf>>,
+    // Paths under the mountpoint of the tracks of cue sheet split FLACs, by FLAC and track index
+    cue_track_paths: HashMap<(PathBuf, usize), PathBuf>,
+    // Modification times of the sources of the directories listed so far, by their path under the
+    // mountpoint
+    listings: HashMap<PathBuf, SystemTime>,
+    // Cue sheets and whole albums by the FLAC or directory in the target directory they were read
+    // from, along with the modification times they were read at, so listings needn't reopen FLACs
+    cue_sheets: HashMap<PathBuf, (Vec<Option<SystemTime>>, Option<(Arc<CueSheet>, u64)>)>,
+    albums: HashMap<PathBuf, (Vec<Option<SystemTime>>, Option<Arc<Album>>)>,
     fds: Arc<Mutex<HashMap<u64, FlacToMp3Encoder<File>>>>,
+    // Listings of the open directories by handle, so readdir can resume at stable offsets
+    dir_handles: HashMap<u64, Vec<(Inode, FileType, OsString)>>,
+    next_dir_handle: u64,
     inode_table: InodeTable
 }
 
 impl Mp3V0Fs {
 
-    pub fn new(target: OsString) -> Mp3V0Fs {
+    pub fn new(target: OsString, options: Options) -> Mp3V0Fs {
+        let loudness_db = match options.loudness_db {
+            Some(ref db_path) => {
+                let loudness_db = Arc::new(Mutex::new(LoudnessDb::open(db_path)));
+                loudness_db::spawn_scan(PathBuf::from(&target), loudness_db.clone());
+                Some(loudness_db)
+            },
+            None => None
+        };
+
+        let library = if options.views {
+            let library = Arc::new(Mutex::new(Library::new(options.sanitize)));
+            // FLACs split by a cue sheet have no MP3 of their own to link to. FLACs whose cue sheet
+            // doesn't split them are presented whole, so they're kept.
+            let split_cue = options.split_cue;
+            views::spawn_scan(PathBuf::from(&target), library.clone(), options.name_template.clone(), move |flac_path| {
+                split_cue && read_cue_sheet(flac_path).is_some()
+            });
+            Some(library)
+        } else {
+            None
+        };
+
+        let mut inode_table = InodeTable::new();
+        inode_table.set_source(ROOT_INODE, Path::new(&target));
+
         Mp3V0Fs {
             target,
+            options,
+            loudness_db,
+            virtual_files: HashMap::new(),
+            library,
+            sources: HashMap::new(),
+            fuse_paths: HashMap::new(),
+            names: HashMap::new(),
+            cue_track_paths: HashMap::new(),
+            listings: HashMap::new(),
+            cue_sheets: HashMap::new(),
+            albums: HashMap::new(),
             fds: Arc::new(Mutex::new(HashMap::new())),
-            inode_table: InodeTable::new()
+            dir_handles: HashMap::new(),
+            next_dir_handle: 1,
+            inode_table
         }
     }
 
-    fn real_path(&self, partial: &Path) -> OsString {
-        let partial = partial.strip_prefix("/").unwrap();
-        let original_candidate = PathBuf::from(&self.target)
-            .join(partial);
+    /// Returns the file or directory in the target directory presented at a path under the
+    /// mountpoint, as listed so far. Paths that weren't listed, e.g. virtual files, have none.
+    fn real_path(&self, fuse_path: &Path) -> Option<PathBuf> {
+        if fuse_path == Path::new("/") {
+            return Some(PathBuf::from(&self.target));
+        }
+        self.sources.get(fuse_path).cloned()
+    }
 
-        if original_candidate.exists() {
-            return original_candidate.into_os_string();
+    /// Returns the file in the target directory the provided inode is read from.
+    fn source_path(&self, ino: Inode, fuse_path: &Path) -> Option<OsString> {
+        match self.virtual_files.get(fuse_path) {
+            Some(VirtualFile::CueTrack { flac_path, .. }) => Some(flac_path.clone().into_os_string()),
+            Some(VirtualFile::Album { album }) => Some(album.tracks[0].flac_path.clone().into_os_string()),
+            None => self.inode_table.get_source(ino).map(|source| source.clone().into_os_string())
         }
+    }
 
-        // If the original candidate didn't exist, assume a FLAC alias does
-        let flac_partial = replace_extension(partial.to_str().unwrap(), FLAC);
-        return PathBuf::from(&self.target)
-            .join(flac_partial)
-            .into_os_string();
+    /// Assigns an inode to a listed path under the mountpoint, recording the file in the target
+    /// directory it's backed by.
+    fn add_inode(&mut self, fuse_path: &Path) -> Inode {
+        let (inode, _path) = self.inode_table.add_or_get_path(fuse_path);
+        if let Some(source) = self.sources.get(fuse_path) {
+            self.inode_table.set_source(inode, source);
+        }
+        inode
     }
 
-    fn fuse_path(&self, real_path: &Path) -> PathBuf {
-        let partial = real_path.strip_prefix(&self.target).unwrap();
+    /// Returns the cue sheet used to split the provided FLAC into tracks, along with the number of
+    /// samples in the FLAC. Returns None if the FLAC isn't split. Cue sheets are read again once
+    /// the FLAC or its sidecar change.
+    fn cue_sheet(&mut self, flac_path: &Path) -> Option<(Arc<CueSheet>, u64)> {
+        if !self.options.split_cue {
+            return None;
+        }
 
-        let fuse_path = PathBuf::from("/");
+        let mtimes = vec![modified(flac_path), modified(&cue::sidecar_path(flac_path))];
+        if let Some((read_mtimes, cue_sheet)) = self.cue_sheets.get(flac_path) {
+            if *read_mtimes == mtimes {
+                return cue_sheet.clone();
+            }
+        }
+
+        let cue_sheet = read_cue_sheet(flac_path)
+            .map(|(cue_sheet, total_samples)| (Arc::new(cue_sheet), total_samples));
+        self.cue_sheets.insert(flac_path.to_path_buf(), (mtimes, cue_sheet.clone()));
+        cue_sheet
+    }
 
-        match parse_extension(partial.to_str().unwrap()).as_ref() {
-            // All FLACs should look like MP3s under the mountpoint
-            FLAC => fuse_path.join(replace_extension(partial.to_str().unwrap(), MP3)),
-            _ => fuse_path.join(partial)
+    /// Registers a virtual file for every track of the provided FLAC if it's split by a cue sheet.
+    /// Tracks are named like the other entries of the directory, so names they share with those or
+    /// with tracks of another FLAC are numbered. Returns the paths of the tracks under the
+    /// mountpoint, which is empty if it isn't split.
+    fn add_cue_tracks(&mut self, fuse_dir: &Path, flac_path: &Path) -> Vec<PathBuf> {
+        let (cue_sheet, total_samples) = match self.cue_sheet(flac_path) {
+            Some(cue_sheet) => cue_sheet,
+            None => return Vec::new()
+        };
+
+        let mut track_paths = Vec::with_capacity(cue_sheet.tracks.len());
+        for index in 0..cue_sheet.tracks.len() {
+            let key = (flac_path.to_path_buf(), index);
+            let track_path = match self.cue_track_paths.get(&key) {
+                Some(track_path) => track_path.clone(),
+                None => {
+                    let name = self.options.sanitize.apply(&cue_sheet.track_file_name(index));
+                    let track_path = self.add_name(fuse_dir, OsStr::new(&name), flac_path);
+                    self.cue_track_paths.insert(key, track_path.clone());
+                    track_path
+                }
+            };
+            self.virtual_files.insert(track_path.clone(), VirtualFile::CueTrack {
+                flac_path: flac_path.to_path_buf(),
+                cue_sheet: cue_sheet.clone(),
+                index,
+                total_samples
+            });
+            track_paths.push(track_path);
+        }
+
+        track_paths
+    }
+
+    /// Registers the whole-album file of a directory if it's enabled and the directory holds an
+    /// album. Returns its path under the mountpoint.
+    fn add_album_file(&mut self, fuse_dir: &Path) -> Option<PathBuf> {
+        if !self.options.album_file {
+            return None;
+        }
+
+        // Real files take precedence
+        let album_path = fuse_dir.join(album::ALBUM_FILE_NAME);
+        if self.sources.contains_key(&album_path) {
+            return None;
         }
+
+        let real_dir = self.real_path(fuse_dir)?;
+        let album = self.album(&real_dir)?;
+        self.virtual_files.insert(album_path.clone(), VirtualFile::Album { album });
+        Some(album_path)
     }
 
-    fn stat(&self, ino: Inode, fuse_path: &PathBuf) -> Result<FileAttr, std::io::Error> {
-        let real_path: OsString = self.real_path(fuse_path);
-        let metadata = match std::fs::metadata(real_path) {
-            Ok(metadata) => metadata,
-            Err(e) => return Err(e)
+    /// Returns the album held by a directory in the target directory. Directories are scanned
+    /// again once they or one of the album's FLACs change.
+    fn album(&mut self, real_dir: &Path) -> Option<Arc<Album>> {
+        let dir_mtime = modified(real_dir);
+        if let Some((scanned_mtimes, album)) = self.albums.get(real_dir) {
+            if *scanned_mtimes == album_mtimes(dir_mtime, album) {
+                return album.clone();
+            }
+        }
+
+        let album = Album::scan(real_dir).map(Arc::new);
+        self.albums.insert(real_dir.to_path_buf(), (album_mtimes(dir_mtime, &album), album.clone()));
+        album
+    }
+
+    /// Lists a directory under the mountpoint: the files and directories of its source under the
+    /// names they are presented as, the tracks of cue sheet split FLACs, the views and the album
+    /// file. Originals are named before transcodes, so when a FLAC and an MP3 share a name the
+    /// collision policy resolves it the same way in `readdir`, `lookup` and `real_path`.
+    fn list_directory(&mut self, fuse_dir: &Path) -> Result<Vec<(PathBuf, FileType)>, std::io::Error> {
+        let real_dir = match self.real_path(fuse_dir) {
+            Some(real_dir) => real_dir,
+            None => return Err(std::io::Error::from(std::io::ErrorKind::NotFound))
+        };
+
+        // Taken before reading, so changes made while listing are picked up by the next lookup
+        if let Some(mtime) = modified(&real_dir) {
+            self.listings.insert(fuse_dir.to_path_buf(), mtime);
+        }
+
+        let mut sources = Vec::new();
+        for dir_entry_result in read_dir(real_dir)? {
+            let dir_entry = match dir_entry_result {
+                Ok(dir_entry) => dir_entry,
+                Err(err) => {
+                    debug!("error reading dir_entry: {}", err);
+                    continue;
+                }
+            };
+            let file_type = match dir_entry.file_type().ok().and_then(adapt_filetype) {
+                Some(FileType::Symlink) if self.options.symlinks == SymlinkPolicy::Follow => {
+                    match follow_symlink(Path::new(&self.target), &dir_entry.path()) {
+                        Some(file_type) => file_type,
+                        None => continue
+                    }
+                },
+                Some(file_type) => file_type,
+                None => continue
+            };
+            sources.push((dir_entry.path(), file_type));
+        }
+        sources.sort_by(|(path, _), (other_path, _)| {
+            (has_extension(path, FLAC), path).cmp(&(has_extension(other_path, FLAC), other_path))
+        });
+
+        let original_names: HashSet<OsString> = sources.iter()
+            .filter(|(path, _)| !has_extension(path, FLAC))
+            .map(|(path, _)| parse_name(path).to_os_string())
+            .collect();
+        let transcode_names: HashSet<OsString> = sources.iter()
+            .filter(|(path, _)| has_extension(path, FLAC))
+            .map(|(path, _)| replace_extension(parse_name(path), MP3))
+            .collect();
+
+        // Sidecars the FLACs of the directory read their lyrics from, whatever the case of .flac
+        let lrc_sidecars: HashSet<PathBuf> = sources.iter()
+            .filter(|(path, _)| has_extension(path, FLAC))
+            .map(|(path, _)| lrc::sidecar_path(path))
+            .collect();
+
+        self.names.entry(fuse_dir.to_path_buf()).or_insert_with(HashMap::new);
+        let mut entries = Vec::with_capacity(sources.len());
+        for (real_path, file_type) in sources {
+            let name = parse_name(&real_path).to_os_string();
+
+            // .lrc sidecars are embedded into the transcoded MP3, so optionally hide them
+            if self.options.hide_lrc && lrc_sidecars.contains(&real_path) {
+                continue;
+            }
+
+            if has_extension(&name, FLAC) {
+                if self.options.collision == CollisionPolicy::Original
+                    && original_names.contains(&replace_extension(&name, MP3)) {
+                    continue;
+                }
+
+                // FLACs split by a cue sheet are replaced by their tracks
+                let track_paths = match file_type {
+                    FileType::RegularFile => self.add_cue_tracks(fuse_dir, &real_path),
+                    _ => Vec::new()
+                };
+                if !track_paths.is_empty() {
+                    entries.extend(track_paths.into_iter().map(|track_path| (track_path, FileType::RegularFile)));
+                    continue;
+                }
+            } else if self.options.collision == CollisionPolicy::Transcode && transcode_names.contains(&name) {
+                continue;
+            }
+
+            entries.push((self.register(fuse_dir, &real_path), file_type));
+        }
+
+        if self.library.is_some() && fuse_dir == Path::new("/") {
+            entries.extend(views::VIEWS.iter().map(|view| (fuse_dir.join(view), FileType::Directory)));
+        }
+
+        if let Some(album_path) = self.add_album_file(fuse_dir) {
+            entries.push((album_path, FileType::RegularFile));
+        }
+
+        Ok(entries)
+    }
+
+    /// Returns the path under the mountpoint of an inode. Inodes the kernel holds are always in the
+    /// inode table, so unknown ones come from stale file handles and aren't searched for.
+    fn inode_path(&self, ino: Inode) -> Option<PathBuf> {
+        self.inode_table.get_path(ino).cloned()
+    }
+
+    /// Lists the entries of a directory under the mountpoint as returned by readdir, including `.`
+    /// and `..`.
+    fn directory_entries(&mut self, ino: Inode, fuse_dir: &Path) -> Result<Vec<(Inode, FileType, OsString)>, std::io::Error> {
+        let parent_inode = fuse_dir.parent()
+            .and_then(|parent| self.inode_table.get_inode(&parent.to_path_buf()))
+            .unwrap_or(ino);
+        let mut entries = vec![
+            (ino, FileType::Directory, OsString::from(".")),
+            (parent_inode, FileType::Directory, OsString::from(".."))
+        ];
+
+        if let Some(view_entries) = self.view_directory(fuse_dir) {
+            for (name, entry) in view_entries {
+                let (inode, fuse_filetype) = match entry {
+                    LibraryEntry::Directory => (self.inode_table.add_or_get_path(&fuse_dir.join(&name)).0, FileType::Directory),
+                    LibraryEntry::Track(flac_path) => {
+                        let target_path = self.add_fuse_path(&flac_path);
+                        (self.add_inode(&target_path), FileType::RegularFile)
+                    }
+                };
+                entries.push((inode, fuse_filetype, OsString::from(name)));
+            }
+            return Ok(entries);
+        }
+
+        for (fuse_path, fuse_filetype) in self.list_directory(fuse_dir)? {
+            let inode = self.add_inode(&fuse_path);
+            entries.push((inode, fuse_filetype, parse_name(&fuse_path).to_os_string()));
+        }
+        Ok(entries)
+    }
+
+    /// Resolves a name within a directory under the mountpoint to the path it's presented at,
+    /// listing the directory if needed. Returns None if there's no such entry.
+    fn lookup_path(&mut self, parent_path: &Path, name: &OsStr) -> Option<PathBuf> {
+        // Tracks within the views share the inode of the file they link to
+        let path = parent_path.join(name);
+        if let Some(flac_path) = self.view_track(&path) {
+            return Some(self.add_fuse_path(&flac_path));
+        }
+
+        // The path may not have been listed by readdir yet. Paths that still aren't listed don't
+        // exist or are hidden, e.g. FLACs split by a cue sheet. Directories are only listed again
+        // once they change, so repeated lookups of missing names stay cheap.
+        if !self.is_listed(&path) && self.is_stale(parent_path) {
+            if let Err(err) = self.list_directory(parent_path) {
+                debug!("lookup: unable to list {:?}: {}", parent_path, err);
+            }
+        }
+        if self.is_listed(&path) {
+            Some(path)
+        } else {
+            None
+        }
+    }
+
+    /// Drops what's known of a path under the mountpoint once the kernel forgets its inode. Its
+    /// directory is listed again on the next lookup, naming it and numbering its inode the same way.
+    fn forget_path(&mut self, fuse_path: &Path) {
+        if let Some(VirtualFile::CueTrack { flac_path, index, .. }) = self.virtual_files.remove(fuse_path) {
+            self.cue_track_paths.remove(&(flac_path, index));
+        }
+        if let Some(real_path) = self.sources.remove(fuse_path) {
+            self.fuse_paths.remove(&real_path);
+        }
+
+        let (fuse_dir, name) = match (fuse_path.parent(), fuse_path.file_name()) {
+            (Some(fuse_dir), Some(name)) => (fuse_dir, name),
+            _ => return
+        };
+        self.listings.remove(fuse_dir);
+        let collision_key = self.options.sanitize.collision_key(name);
+        let no_names_left = match self.names.get_mut(fuse_dir) {
+            Some(names) => {
+                names.remove(&collision_key);
+                names.is_empty()
+            },
+            None => false
+        };
+        if no_names_left {
+            self.names.remove(fuse_dir);
+        }
+    }
+
+    /// Returns whether a directory under the mountpoint was never listed or its source changed since.
+    fn is_stale(&self, fuse_dir: &Path) -> bool {
+        let listed = match self.listings.get(fuse_dir) {
+            Some(listed) => listed,
+            None => return true
+        };
+        match self.real_path(fuse_dir).and_then(|real_dir| modified(&real_dir)) {
+            Some(mtime) => mtime != *listed,
+            None => true
+        }
+    }
+
+    /// Returns whether a path under the mountpoint is presented, as listed so far.
+    fn is_listed(&self, fuse_path: &Path) -> bool {
+        self.sources.contains_key(fuse_path)
+            || self.virtual_files.contains_key(fuse_path)
+            || self.is_view_directory(fuse_path)
+    }
+
+    /// Returns whether a path under the mountpoint is a directory within the views.
+    fn is_view_directory(&self, fuse_path: &Path) -> bool {
+        match self.library {
+            Some(ref library) => library.lock().unwrap().is_directory(fuse_path),
+            None => false
+        }
+    }
+
+    /// Returns the FLAC backing a track within the views.
+    fn view_track(&self, fuse_path: &Path) -> Option<PathBuf> {
+        let library = self.library.as_ref()?.lock().unwrap();
+        match library.get(fuse_path) {
+            Some(LibraryEntry::Track(flac_path)) => Some(flac_path.clone()),
+            _ => None
+        }
+    }
+
+    /// Returns the entries of a directory within the views, or None if the path isn't one.
+    fn view_directory(&self, fuse_path: &Path) -> Option<Vec<(String, LibraryEntry)>> {
+        let library = self.library.as_ref()?.lock().unwrap();
+        let entries = library.list(fuse_path)?;
+        Some(entries.iter().map(|(name, entry)| (name.clone(), entry.clone())).collect())
+    }
+
+    fn stored_replay_gain(&self, source_path: &Path) -> Option<ReplayGain> {
+        match (&self.loudness_db, loudness_db::modification_time(source_path)) {
+            (Some(loudness_db), Some(mtime)) => loudness_db.lock().unwrap()
+                .get(source_path, mtime)
+                .cloned(),
+            _ => None
+        }
+    }
+
+    /// Builds the name of a FLAC from the name template, if one is set.
+    fn template_name(&self, real_path: &Path) -> Option<String> {
+        let name_template = self.options.name_template.as_ref()?;
+        if !has_extension(real_path, FLAC) {
+            return None;
+        }
+
+        let flac_reader = FlacReader::open(real_path).ok()?;
+        name_template.render(&encode::vorbis_comments(&flac_reader))
+    }
+
+    /// Returns the path under the mountpoint of a file or directory in the target directory. Its
+    /// directory is listed first, so it's named the same way as when listed by `readdir`.
+    fn add_fuse_path(&mut self, real_path: &Path) -> PathBuf {
+        if real_path == Path::new(&self.target) {
+            return PathBuf::from("/");
+        }
+        if let Some(fuse_path) = self.fuse_paths.get(real_path) {
+            return fuse_path.clone();
+        }
+
+        let fuse_dir = match real_path.parent() {
+            Some(real_dir) => self.add_fuse_path(real_dir),
+            None => PathBuf::from("/")
+        };
+        if !self.names.contains_key(&fuse_dir) {
+            if let Err(err) = self.list_directory(&fuse_dir) {
+                warn!("Unable to list {:?}: {}", fuse_dir, err);
+            }
+        }
+
+        // Files hidden by the collision policy can still be linked to, e.g. from the views
+        self.register(&fuse_dir, real_path)
+    }
+
+    /// Reads the target of a symlink in the target directory as presented under the mountpoint.
+    /// Targets within the target directory are given relative to the link as the path they are
+    /// presented at, so they follow templated, sanitized and numbered names and absolute targets
+    /// don't lead out of the mountpoint. Other targets are presented as they are by `link_target`.
+    fn resolve_link(&mut self, link_path: &Path, fuse_link_path: &Path) -> Result<OsString, std::io::Error> {
+        let target = std::fs::read_link(link_path)?;
+        let real_target = match self.real_link_target(link_path, &target) {
+            Some(real_target) => real_target,
+            None => return link_target(link_path)
+        };
+
+        let fuse_target = self.add_fuse_path(&real_target);
+        let fuse_dir = fuse_link_path.parent().unwrap_or(Path::new("/"));
+        Ok(relative_path(fuse_dir, &fuse_target).into_os_string())
+    }
+
+    /// Returns the file or directory in the target directory a symlink points at, if it exists and
+    /// is within the target directory. Targets that are links themselves are kept as they are.
+    fn real_link_target(&self, link_path: &Path, target: &Path) -> Option<PathBuf> {
+        let absolute_target = link_path.parent()?.join(target);
+        let canonical_target = match absolute_target.file_name() {
+            Some(name) => std::fs::canonicalize(absolute_target.parent()?).ok()?.join(name),
+            None => std::fs::canonicalize(&absolute_target).ok()?
+        };
+        let canonical_root = std::fs::canonicalize(&self.target).ok()?;
+
+        let real_target = Path::new(&self.target).join(canonical_target.strip_prefix(&canonical_root).ok()?);
+        match std::fs::symlink_metadata(&real_target) {
+            Ok(_) => Some(real_target),
+            Err(_) => None
+        }
+    }
+
+    /// Names a file or directory of the target directory within its directory under the mountpoint.
+    /// Names are built from the name template and sanitized as configured, then numbered if they
+    /// collide with an entry named before. Names are kept once registered.
+    fn register(&mut self, fuse_dir: &Path, real_path: &Path) -> PathBuf {
+        if let Some(fuse_path) = self.fuse_paths.get(real_path) {
+            return fuse_path.clone();
+        }
+
+        let name = presented_name(real_path, self.template_name(real_path), self.options.sanitize);
+        let fuse_path = self.add_name(fuse_dir, &name, real_path);
+        self.sources.insert(fuse_path.clone(), real_path.to_path_buf());
+        self.fuse_paths.insert(real_path.to_path_buf(), fuse_path.clone());
+        fuse_path
+    }
+
+    /// Claims a name within a directory under the mountpoint for an entry read from the provided
+    /// source, numbering it if it collides with a name claimed before. Returns the path the entry
+    /// is presented at.
+    fn add_name(&mut self, fuse_dir: &Path, name: &OsStr, source: &Path) -> PathBuf {
+        let sanitize_mode = self.options.sanitize;
+        let names = self.names.entry(fuse_dir.to_path_buf()).or_insert_with(HashMap::new);
+        let mut unique_name = name.to_os_string();
+        let mut counter = 1;
+        while names.contains_key(&sanitize_mode.collision_key(&unique_name)) {
+            counter += 1;
+            unique_name = sanitize::numbered(name, counter);
+        }
+        names.insert(sanitize_mode.collision_key(&unique_name), source.to_path_buf());
+        fuse_dir.join(&unique_name)
+    }
+
+    fn stat(&mut self, ino: Inode, fuse_path: &PathBuf) -> Result<FileAttr, std::io::Error> {
+        let (metadata, size) = match self.virtual_files.get(fuse_path) {
+            Some(VirtualFile::CueTrack { flac_path, cue_sheet, index, total_samples }) => {
+                let metadata = std::fs::metadata(flac_path)?;
+                // Share of the whole album estimate, proportional to the track length
+                let track = &cue_sheet.tracks[*index];
+                let track_samples = track.end.unwrap_or(*total_samples).saturating_sub(track.start);
+                let size = metadata.size() * 2 * track_samples / (*total_samples).max(1);
+                (metadata, size)
+            },
+            Some(VirtualFile::Album { album }) => {
+                let metadata = std::fs::metadata(&album.tracks[0].flac_path)?;
+                let mut size = 0;
+                for track in &album.tracks {
+                    size += std::fs::metadata(&track.flac_path)?.size() * 2;
+                }
+                (metadata, size)
+            },
+            None if self.is_view_directory(fuse_path) => {
+                // View directories take on the attributes of the source directory
+                let metadata = std::fs::metadata(&self.target)?;
+                let size = metadata.size();
+                (metadata, size)
+            },
+            None => {
+                let real_path = match self.inode_table.get_source(ino) {
+                    Some(real_path) => real_path.clone(),
+                    None => return Err(std::io::Error::from(std::io::ErrorKind::NotFound))
+                };
+                let metadata = match self.options.symlinks {
+                    SymlinkPolicy::Preserve => std::fs::symlink_metadata(&real_path)?,
+                    SymlinkPolicy::Follow => std::fs::metadata(&real_path)?
+                };
+                let size = if metadata.file_type().is_symlink() {
+                    self.resolve_link(&real_path, fuse_path)?.len() as u64
+                } else {
+                    // TODO calculate
+                    metadata.size() * 2
+                };
+                (metadata, size)
+            }
         };
 
         let fuse_filetype = match adapt_filetype(metadata.file_type()) {
@@ -76,8 +625,7 @@ impl Mp3V0Fs {
 
         Ok(fuse::FileAttr {
             ino,
-            // TODO calculate
-            size: metadata.size() * 2,
+            size,
             blocks: metadata.blocks(),
             //TODO error checking
             atime: metadata.accessed().unwrap(),
@@ -97,9 +645,17 @@ impl Mp3V0Fs {
 
 impl Filesystem for Mp3V0Fs {
     fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        let (inode, path) = self.inode_table.add_or_get(parent, name);
-        debug!("lookup: {:?}, {:?}", inode, path);
+        let parent_path = match self.inode_path(parent) {
+            Some(parent_path) => parent_path,
+            None => return reply.error(libc::ESTALE)
+        };
 
+        let path = match self.lookup_path(&parent_path, name) {
+            Some(path) => path,
+            None => return reply.error(1)
+        };
+        let inode = self.add_inode(&path);
+        debug!("lookup: {:?}, {:?} via {:?}", inode, path, parent_path.join(name));
         self.inode_table.lookup(inode);
 
         match self.stat(inode, &path) {
@@ -110,14 +666,17 @@ impl Filesystem for Mp3V0Fs {
 
     fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
         debug!("forget: {:?}, {:?}", ino, nlookup);
-        self.inode_table.forget(ino, nlookup);
+        let path = self.inode_table.get_path(ino).cloned();
+        if let (Some(path), Some(0)) = (path, self.inode_table.forget(ino, nlookup)) {
+            self.forget_path(&path);
+        }
     }
 
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        let path = match self.inode_table.get_path(ino) {
+        let path = match self.inode_path(ino) {
             Some(path) => path,
-            None => return reply.error(1)
-        }.to_owned();
+            None => return reply.error(libc::ESTALE)
+        };
         debug!("getattr: {:?}", path);
 
         match self.stat(ino, &path) {
@@ -126,29 +685,75 @@ impl Filesystem for Mp3V0Fs {
         };
     }
 
-    fn readlink(&mut self, _req: &Request, ino: u64, _reply: ReplyData) {
-        //TODO needed?
-        debug!("readlink: {:?}", ino);
-        unimplemented!()
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        let path = match self.inode_path(ino) {
+            Some(path) => path,
+            None => return reply.error(libc::ESTALE)
+        };
+        let real_path = match self.inode_table.get_source(ino) {
+            Some(real_path) => real_path.clone(),
+            None => return reply.error(1)
+        };
+        debug!("readlink: {:?}, {:?}", ino, real_path);
+
+        match self.resolve_link(&real_path, &path) {
+            Ok(target) => reply.data(target.as_bytes()),
+            //TODO error code enum
+            Err(_e) => reply.error(1)
+        };
     }
 
     fn open(&mut self, _req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
-        let path = match self.inode_table.get_path(ino) {
+        let path = match self.inode_path(ino) {
             Some(path) => path,
-            None => return reply.error(1)
-        }.to_owned();
+            None => return reply.error(libc::ESTALE)
+        };
         debug!("open: {:?}, {:?}", path, flags);
 
-        let real_path = self.real_path(&path);
+        let real_path = match self.source_path(ino, &path) {
+            Some(real_path) => real_path,
+            None => return reply.error(1)
+        };
         let mut fds = self.fds.lock().unwrap();
 
         if !fds.contains_key(&ino) {
+            if let Some(VirtualFile::Album { album }) = self.virtual_files.get(&path) {
+                let mut flac_readers = Vec::with_capacity(album.tracks.len());
+                for track in &album.tracks {
+                    match FlacReader::open(&track.flac_path) {
+                        Ok(flac_reader) => flac_readers.push(flac_reader),
+                        Err(err) => {
+                            warn!("Error opening file {:?}. {}", track.flac_path, err);
+                            return reply.error(libc::EIO);
+                        }
+                    }
+                }
+
+                let encoder = match FlacToMp3Encoder::new_album(flac_readers, album, &self.options) {
+                    Some(encoder) => encoder,
+                    None => return reply.error(libc::EIO)
+                };
+                debug!("adding ino={} to fds for album={:?}", ino, path);
+                fds.insert(ino, encoder);
+                return reply.opened(ino, flags);
+            }
+
             let flac_reader = match FlacReader::open(real_path.to_owned()) {
                 Ok(flac_reader) => flac_reader,
-                Err(err) => panic!("Error opening file {}. {}", path.to_str().unwrap(), err)
+                Err(err) => {
+                    warn!("Error opening file {:?}. {}", path, err);
+                    return reply.error(libc::EIO);
+                }
             };
 
-            let encoder = FlacToMp3Encoder::new(flac_reader);
+            let stored_replay_gain = self.stored_replay_gain(Path::new(&real_path));
+
+            let encoder = match self.virtual_files.get(&path) {
+                Some(VirtualFile::CueTrack { cue_sheet, index, .. }) => FlacToMp3Encoder::new_cue_track(
+                    flac_reader, cue_sheet, *index, stored_replay_gain, &self.options
+                ),
+                _ => FlacToMp3Encoder::new(flac_reader, Path::new(&real_path), stored_replay_gain, &self.options)
+            };
 
             debug!("adding ino={} to fds for real_path={:?}", ino, real_path);
             fds.insert(ino, encoder);
@@ -194,72 +799,37 @@ impl Filesystem for Mp3V0Fs {
     }
 
     fn opendir(&mut self, _req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
-        let path = match self.inode_table.get_path(ino) {
+        let path = match self.inode_path(ino) {
             Some(path) => path,
-            // TODO error code enum
-            None => return reply.error(1)
-        }.to_owned();
+            None => return reply.error(libc::ESTALE)
+        };
         debug!("opendir: {:?}, {:?}", path, flags);
 
-        // inode number is always be unique per file so should be an acceptable replacement for the
-        // fh u64 expected in ReplyOpen
-        reply.opened(ino, flags);
-    }
+        // The listing is taken once per handle, so offsets stay valid while it's read in chunks
+        let entries = match self.directory_entries(ino, &path) {
+            Ok(entries) => entries,
+            //TODO error code enum
+            Err(_e) => return reply.error(1)
+        };
+        let fh = self.next_dir_handle;
+        self.next_dir_handle += 1;
+        self.dir_handles.insert(fh, entries);
 
-    fn readdir(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
-        //TODO handle chunking responses
-        if offset > 0 {
-            reply.ok();
-            return;
-        }
+        reply.opened(fh, flags);
+    }
 
-        let path = match self.inode_table.get_path(ino) {
-            Some(path) => path,
+    fn readdir(&mut self, _req: &Request, ino: u64, fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        debug!("readdir: {:?}, {:?}, {:?}", ino, fh, offset);
+        let entries = match self.dir_handles.get(&fh) {
+            Some(entries) => entries,
             // TODO error code enum
-            None => {
-                reply.error(1);
-                return;
-            }
-        }.to_owned();
-        debug!("readdir: {:?}", path);
-
-        let real_path = self.real_path(&path);
-        let entries = match read_dir(real_path) {
-            Ok(read_dir) => read_dir,
-            Err(_e) => {
-                //TODO error code enum
-                reply.error(1);
-                return;
-            }
+            None => return reply.error(1)
         };
 
-        for (index, dir_entry_result) in entries.enumerate() {
-            if dir_entry_result.is_err() {
-                debug!("error reading dir_entry: {}", dir_entry_result.err().unwrap());
-                continue;
-            }
-            let dir_entry = dir_entry_result.unwrap();
-
-            let fuse_path = self.fuse_path(dir_entry.path().as_path());
-            let (inode, _path) = self.inode_table.add_or_get(ino, fuse_path.clone().as_os_str());
-
-            let fuse_filetype = match dir_entry.file_type() {
-                Ok(fs_filetype) => match adapt_filetype(fs_filetype) {
-                    Some(fuse_filetype) => fuse_filetype,
-                    None => continue
-                },
-                Err(_e) => {
-                    //TODO error code enum
-                    reply.error(1);
-                    return;
-                }
-            };
-
-            let fuse_filename = parse_name(fuse_path.as_path().to_str().unwrap());
-
-            // Start offset at 1 to avoid looping forever on directory with only 1 entry
-            let buffer_full = reply.add(inode, 1 + index as i64, fuse_filetype, fuse_filename);
-            if buffer_full {
+        // The offset of each entry is that of the next one, so a listing resumes after the last
+        // entry returned
+        for (index, (inode, fuse_filetype, name)) in entries.iter().enumerate().skip(offset as usize) {
+            if reply.add(*inode, 1 + index as i64, *fuse_filetype, name) {
                 debug!("readdir reply buffer full");
                 break;
             }
@@ -268,14 +838,23 @@ impl Filesystem for Mp3V0Fs {
         reply.ok();
     }
 
+    fn releasedir(&mut self, _req: &Request, ino: u64, fh: u64, flags: u32, reply: ReplyEmpty) {
+        debug!("releasedir: {:?}, {:?}, {:?}", ino, fh, flags);
+        self.dir_handles.remove(&fh);
+        reply.ok();
+    }
+
     fn getxattr(&mut self, _req: &Request, inode: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
-        let path = match self.inode_table.get_path(inode) {
+        let path = match self.inode_path(inode) {
             Some(path) => path,
-            None => return reply.error(1)
+            None => return reply.error(libc::ESTALE)
         };
         debug!("getxattr: {:?}, {:?}, {:?}, {:?}", path, inode, name, size);
 
-        let real_path = self.real_path(path);
+        let real_path = match self.source_path(inode, &path) {
+            Some(real_path) => real_path,
+            None => return reply.error(1)
+        };
 
         if size == 0 {
             let size = unsafe {
@@ -301,13 +880,16 @@ impl Filesystem for Mp3V0Fs {
     }
 
     fn listxattr(&mut self, _req: &Request, inode: u64, size: u32, reply: ReplyXattr) {
-        let path = match self.inode_table.get_path(inode) {
+        let path = match self.inode_path(inode) {
             Some(path) => path,
-            None => return reply.error(1)
+            None => return reply.error(libc::ESTALE)
         };
         debug!("listxattr: {:?}, {:?}, {:?}", path, inode, size);
 
-        let real_path = self.real_path(path);
+        let real_path = match self.source_path(inode, &path) {
+            Some(real_path) => real_path,
+            None => return reply.error(1)
+        };
 
         if size == 0 {
             let size = unsafe {
@@ -343,58 +925,176 @@ fn adapt_filetype(fs_filetype: std::fs::FileType) -> Option<FileType> {
     }
 }
 
-/// Parses out the name of a file given a path.
-fn parse_name(path: &str) -> String {
-    let path_components: Vec<&str> = path.split("/").collect();
-    if path_components.len() == 0 {
-        return String::from("")
+/// Resolves a symlink in the target directory `root` to the type of what it points at, for the
+/// follow policy. Links that are dangling or point outside of `root` are left out, as are links to
+/// a directory on the path to the link, e.g. `a/l -> ../b` next to `b/m -> ../a`, which would
+/// otherwise present an endless tree.
+fn follow_symlink(root: &Path, link_path: &Path) -> Option<FileType> {
+    let canonical_root = std::fs::canonicalize(root).ok()?;
+    // Dangling links and loops between links fail to resolve
+    let link_target = match std::fs::canonicalize(link_path) {
+        Ok(link_target) => link_target,
+        Err(err) => {
+            debug!("not following {:?}: {}", link_path, err);
+            return None;
+        }
+    };
+
+    if !link_target.starts_with(&canonical_root) {
+        debug!("not following {:?}, it points outside of {:?}", link_path, canonical_root);
+        return None;
     }
 
-    path_components[path_components.len() - 1].to_owned()
+    // Directories visited on the way to the link, which may itself be reached through links
+    let visited: HashSet<PathBuf> = link_path.ancestors()
+        .skip(1)
+        .take_while(|ancestor| ancestor.starts_with(root))
+        .filter_map(|ancestor| std::fs::canonicalize(ancestor).ok())
+        .collect();
+    if visited.iter().any(|directory| directory.starts_with(&link_target)) {
+        debug!("not following {:?}, it points at a directory on its own path", link_path);
+        return None;
+    }
+
+    adapt_filetype(std::fs::metadata(&link_target).ok()?.file_type())
 }
 
-/// Parse out the file extension given the path to a file.
-fn parse_extension(path: &str) -> String {
-    let path_components: Vec<&str> = path.split("/").collect();
-    if path_components.len() == 0 {
-        return String::from("")
+/// Returns the name a file or directory of the target directory is presented under before it's
+/// numbered, given the name rendered from the name template if it's a FLAC and one is set. Names
+/// are sanitized as configured.
+pub(crate) fn presented_name(real_path: &Path, template_name: Option<String>, sanitize: SanitizeMode) -> OsString {
+    let name = match template_name {
+        Some(template_name) => OsString::from(template_name),
+        // All FLACs should look like MP3s under the mountpoint
+        None if has_extension(real_path, FLAC) => replace_extension(parse_name(real_path), MP3),
+        None => parse_name(real_path).to_os_string()
+    };
+    sanitize.apply_os(&name)
+}
+
+/// Returns the modification time of a file or directory in the target directory.
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// Reads the cue sheet used to split the provided FLAC into tracks, along with the number of
+/// samples in the FLAC.
+fn read_cue_sheet(flac_path: &Path) -> Option<(CueSheet, u64)> {
+    // A .cue sidecar takes precedence over a CUESHEET block embedded in the FLAC
+    let embedded_cue_sheet = if cue::sidecar_path(flac_path).exists() {
+        None
+    } else {
+        Some(cue::read_embedded(flac_path)?)
+    };
+
+    let flac_reader = FlacReader::open(flac_path).ok()?;
+    let stream_info = flac_reader.streaminfo();
+    let cue_sheet = match embedded_cue_sheet {
+        Some(mut cue_sheet) => {
+            cue_sheet.add_track_comments(&encode::vorbis_comments(&flac_reader));
+            cue_sheet
+        },
+        None => cue::read_sidecar(flac_path, stream_info.sample_rate)?
+    };
+
+    Some((cue_sheet, stream_info.samples?))
+}
+
+/// Modification times an album scan depends on: those of its directory and of its FLACs, which
+/// may be retagged in place.
+fn album_mtimes(dir_mtime: Option<SystemTime>, album: &Option<Arc<Album>>) -> Vec<Option<SystemTime>> {
+    let mut mtimes = vec![dir_mtime];
+    if let Some(album) = album {
+        mtimes.extend(album.tracks.iter().map(|track| modified(&track.flac_path)));
     }
-    let file_name = path_components[path_components.len() - 1];
+    mtimes
+}
 
-    let name_and_extension: Vec<&str> = file_name.split(".").collect();
-    match name_and_extension.len() {
-        0 | 1 => String::from(""),
-        _ => String::from(name_and_extension[name_and_extension.len() - 1])
+/// Reads the target of a symlink pointing outside of the target directory or at nothing, as
+/// presented under the mountpoint, where links to FLACs point at their MP3s.
+fn link_target(link_path: &Path) -> Result<OsString, std::io::Error> {
+    let target = std::fs::read_link(link_path)?;
+    if has_extension(&target, FLAC) {
+        Ok(replace_extension(&target, MP3))
+    } else {
+        Ok(target.into_os_string())
     }
 }
 
-/// Replaces the extension of a file with the provided replacement
-fn replace_extension(path: &str, replacement: &str) -> String {
-    let mut path_components: Vec<&str> = path.split("/").collect();
-    if path_components.len() == 0 {
-        return String::from("")
-    }
-    let file_name = path_components[path_components.len() - 1];
-
-    let mut name_and_extension: Vec<&str> = file_name.split(".").collect();
-    let new_filename = match name_and_extension.len() {
-        0 => String::from(""),
-        1 => String::from(name_and_extension[0]),
-        _ => {
-            name_and_extension.remove(name_and_extension.len() - 1);
-            name_and_extension.push(replacement);
-            name_and_extension.join(".")
-        }
-    };
+/// Builds the path leading from a directory under the mountpoint to another path under it.
+fn relative_path(from_dir: &Path, to_path: &Path) -> PathBuf {
+    let from_components: Vec<Component> = from_dir.components().collect();
+    let to_components: Vec<Component> = to_path.components().collect();
+    let common = from_components.iter().zip(to_components.iter())
+        .take_while(|(from, to)| from == to)
+        .count();
+
+    let mut relative = PathBuf::new();
+    for _ in common..from_components.len() {
+        relative.push("..");
+    }
+    for component in &to_components[common..] {
+        relative.push(component);
+    }
+    if relative.as_os_str().is_empty() {
+        relative.push(".");
+    }
+    relative
+}
+
+/// Parses out the name of a file given a path. Paths are handled as bytes, as source names
+/// needn't be valid UTF-8.
+fn parse_name<P: AsRef<OsStr> + ?Sized>(path: &P) -> &OsStr {
+    let path = path.as_ref().as_bytes();
+    match path.iter().rposition(|&byte| byte == b'/') {
+        Some(index) => OsStr::from_bytes(&path[index + 1..]),
+        None => OsStr::from_bytes(path)
+    }
+}
+
+/// Parse out the file extension given the path to a file.
+fn parse_extension<P: AsRef<OsStr> + ?Sized>(path: &P) -> &OsStr {
+    let file_name = parse_name(path).as_bytes();
+    match file_name.iter().rposition(|&byte| byte == b'.') {
+        Some(index) => OsStr::from_bytes(&file_name[index + 1..]),
+        None => OsStr::new("")
+    }
+}
 
-    path_components.remove(path_components.len() - 1);
-    path_components.push(&new_filename);
-    path_components.join("/")
+/// Checks the extension of a file, ignoring case as e.g. rips made on Windows often end in .FLAC.
+pub(crate) fn has_extension<P: AsRef<OsStr> + ?Sized>(path: &P, extension: &str) -> bool {
+    parse_extension(path).as_bytes().eq_ignore_ascii_case(extension.as_bytes())
+}
+
+/// Replaces the extension of a file with the provided replacement
+fn replace_extension<P: AsRef<OsStr> + ?Sized>(path: &P, replacement: &str) -> OsString {
+    let path = path.as_ref().as_bytes();
+    let name_start = path.iter().rposition(|&byte| byte == b'/').map_or(0, |index| index + 1);
+    match path[name_start..].iter().rposition(|&byte| byte == b'.') {
+        Some(index) => {
+            let mut replaced = path[..name_start + index + 1].to_vec();
+            replaced.extend_from_slice(replacement.as_bytes());
+            OsString::from_vec(replaced)
+        },
+        None => OsStr::from_bytes(path).to_os_string()
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::mp3v0fs::{MP3, parse_extension, parse_name, replace_extension};
+    use crate::mp3v0fs::{
+        FLAC, MP3, Mp3V0Fs, follow_symlink, has_extension, link_target, parse_extension, parse_name, relative_path,
+        replace_extension
+    };
+    use crate::options::Options;
+    use crate::sanitize::SanitizeMode;
+    use fuse::FileType;
+    use std::ffi::OsStr;
+    use std::fs::{File, create_dir};
+    use std::path::{Path, PathBuf};
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::symlink;
+    use tempfile::TempDir;
 
     #[test]
     fn test_parse_name() {
@@ -422,6 +1122,16 @@ mod tests {
         assert_eq!("mp3", parse_extension("/home/user/music/test.mp3"));
     }
 
+    #[test]
+    fn test_has_extension() {
+        assert!(has_extension("test.flac", FLAC));
+        assert!(has_extension("/home/user/music/test.FLAC", FLAC));
+        assert!(has_extension("music/test.Flac", FLAC));
+        assert!(!has_extension("test.mp3", FLAC));
+        assert!(!has_extension("test", FLAC));
+        assert!(!has_extension("flac/test", FLAC));
+    }
+
     #[test]
     fn test_replace_extension() {
         assert_eq!("", replace_extension("", MP3));
@@ -430,6 +1140,7 @@ mod tests {
         assert_eq!("/home/user/music/test", replace_extension("/home/user/music/test", MP3));
         assert_eq!("test.mp3", replace_extension("test.flac", MP3));
         assert_eq!("test.mp3", replace_extension("test.mp3", MP3));
+        assert_eq!("test.mp3", replace_extension("test.FLAC", MP3));
         assert_eq!("./test.mp3", replace_extension("./test.flac", MP3));
         assert_eq!("./test.mp3", replace_extension("./test.mp3", MP3));
         assert_eq!("music/test.mp3", replace_extension("music/test.flac", MP3));
@@ -437,4 +1148,156 @@ mod tests {
         assert_eq!("/home/user/music/test.mp3", replace_extension("/home/user/music/test.flac", MP3));
         assert_eq!("/home/user/music/test.mp3", replace_extension("/home/user/music/test.mp3", MP3));
     }
+
+    #[test]
+    fn test_non_utf8_paths() {
+        // Latin-1 encoded "/music/Björk/Jóga.flac"
+        let path = OsStr::from_bytes(b"/music/Bj\xf6rk/J\xf3ga.flac");
+
+        assert_eq!(OsStr::from_bytes(b"J\xf3ga.flac"), parse_name(path));
+        assert_eq!("flac", parse_extension(path));
+        assert_eq!(OsStr::from_bytes(b"/music/Bj\xf6rk/J\xf3ga.mp3"), replace_extension(path, MP3));
+        assert_eq!(path, replace_extension(OsStr::from_bytes(b"/music/Bj\xf6rk/J\xf3ga.mp3"), FLAC));
+
+        // Dots in directory names aren't extensions
+        let path = OsStr::from_bytes(b"/music/Bj\xf6rk.v2/J\xf3ga");
+        assert_eq!("", parse_extension(path));
+        assert_eq!(path, replace_extension(path, MP3));
+    }
+
+    #[test]
+    fn test_link_target() {
+        let dir = TempDir::new().unwrap();
+        symlink("../album/01 - a.flac", dir.path().join("a.flac")).unwrap();
+        symlink("../album", dir.path().join("album")).unwrap();
+
+        assert_eq!("../album/01 - a.mp3", link_target(&dir.path().join("a.flac")).unwrap());
+        assert_eq!("../album", link_target(&dir.path().join("album")).unwrap());
+        assert!(link_target(dir.path()).is_err());
+    }
+
+    #[test]
+    fn test_resolve_link() {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path().join("music");
+        create_dir(&root).unwrap();
+        create_dir(root.join("album")).unwrap();
+        create_dir(root.join("links")).unwrap();
+        File::create(root.join("album/01: a.flac")).unwrap();
+        symlink("../album/01: a.flac", root.join("links/relative.flac")).unwrap();
+        symlink(root.join("album/01: a.flac"), root.join("links/absolute.flac")).unwrap();
+        symlink("../album", root.join("links/album")).unwrap();
+        symlink("..", root.join("links/root")).unwrap();
+        symlink("../../outside.flac", root.join("links/outside.flac")).unwrap();
+
+        let mut options = Options::default();
+        options.sanitize = SanitizeMode::Vfat;
+        let mut fs = Mp3V0Fs::new(root.clone().into_os_string(), options);
+        let resolve_link = |fs: &mut Mp3V0Fs, name: &str| {
+            let link_path = root.join("links").join(name);
+            let fuse_link_path = fs.add_fuse_path(&link_path);
+            fs.resolve_link(&link_path, &fuse_link_path).unwrap()
+        };
+
+        // Targets within the target directory point at their sanitized MP3, even if absolute
+        assert_eq!("../album/01_ a.mp3", resolve_link(&mut fs, "relative.flac"));
+        assert_eq!("../album/01_ a.mp3", resolve_link(&mut fs, "absolute.flac"));
+        assert_eq!("../album", resolve_link(&mut fs, "album"));
+        assert_eq!("..", resolve_link(&mut fs, "root"));
+
+        // Other targets only have their extension replaced
+        assert_eq!("../../outside.mp3", resolve_link(&mut fs, "outside.flac"));
+    }
+
+    #[test]
+    fn test_relative_path() {
+        assert_eq!(Path::new("01 - a.mp3"), relative_path(Path::new("/album"), Path::new("/album/01 - a.mp3")));
+        assert_eq!(Path::new("../b/a.mp3"), relative_path(Path::new("/a"), Path::new("/b/a.mp3")));
+        assert_eq!(Path::new("../.."), relative_path(Path::new("/a/b"), Path::new("/")));
+        assert_eq!(Path::new("a"), relative_path(Path::new("/"), Path::new("/a")));
+        assert_eq!(Path::new("."), relative_path(Path::new("/a"), Path::new("/a")));
+    }
+
+    #[test]
+    fn test_follow_symlink() {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path().join("music");
+        create_dir(&root).unwrap();
+        create_dir(root.join("a")).unwrap();
+        create_dir(root.join("b")).unwrap();
+        File::create(root.join("a/01 - a.flac")).unwrap();
+        symlink("01 - a.flac", root.join("a/a.flac")).unwrap();
+        symlink("../b", root.join("a/l")).unwrap();
+        symlink("../a", root.join("b/m")).unwrap();
+        symlink("..", root.join("a/up")).unwrap();
+
+        assert_eq!(Some(FileType::RegularFile), follow_symlink(&root, &root.join("a/a.flac")));
+        assert_eq!(Some(FileType::Directory), follow_symlink(&root, &root.join("a/l")));
+        assert_eq!(Some(FileType::Directory), follow_symlink(&root, &root.join("b/m")));
+
+        // Loops between links and links to a parent
+        assert_eq!(None, follow_symlink(&root, &root.join("a/l/m")));
+        assert_eq!(None, follow_symlink(&root, &root.join("b/m/l")));
+        assert_eq!(None, follow_symlink(&root, &root.join("a/up")));
+
+        // Links out of the root
+        File::create(dir.path().join("outside.flac")).unwrap();
+        symlink("../../outside.flac", root.join("a/outside.flac")).unwrap();
+        symlink(dir.path(), root.join("outside")).unwrap();
+        assert_eq!(None, follow_symlink(&root, &root.join("a/outside.flac")));
+        assert_eq!(None, follow_symlink(&root, &root.join("outside")));
+
+        // Dangling links
+        symlink("missing.flac", root.join("a/dangling.flac")).unwrap();
+        assert_eq!(None, follow_symlink(&root, &root.join("a/dangling.flac")));
+    }
+
+    #[test]
+    fn test_cue_track_names() {
+        let dir = TempDir::new().unwrap();
+        let flac_path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/resources/C1.flac");
+        let cue_sheet = "FILE \"album.flac\" WAVE\n\
+            TRACK 01 AUDIO\nTITLE \"First\"\nINDEX 01 00:00:00\n\
+            TRACK 02 AUDIO\nTITLE \"Second\"\nINDEX 01 00:00:10\n";
+        for album in &["a", "b"] {
+            std::fs::copy(&flac_path, dir.path().join(format!("{}.flac", album))).unwrap();
+            std::fs::write(dir.path().join(format!("{}.cue", album)), cue_sheet).unwrap();
+        }
+        File::create(dir.path().join("01 - First.mp3")).unwrap();
+        let mut fs = Mp3V0Fs::new(dir.path().as_os_str().to_os_string(), Options::default());
+
+        // Tracks sharing a name with a real file or with tracks of another FLAC are numbered
+        let names = |fs: &mut Mp3V0Fs| -> Vec<PathBuf> {
+            fs.list_directory(Path::new("/")).unwrap().into_iter()
+                .map(|(path, _)| path)
+                .filter(|path| has_extension(path, MP3))
+                .collect()
+        };
+        let expected: Vec<PathBuf> = vec![
+            "/01 - First.mp3", "/01 - First (2).mp3", "/02 - Second.mp3", "/01 - First (3).mp3", "/02 - Second (2).mp3"
+        ].into_iter().map(PathBuf::from).collect();
+        assert_eq!(expected, names(&mut fs));
+        assert_eq!(Some(dir.path().join("01 - First.mp3")), fs.real_path(Path::new("/01 - First.mp3")));
+        assert!(fs.virtual_files.contains_key(Path::new("/02 - Second (2).mp3")));
+
+        // Names are kept when listed again
+        assert_eq!(expected, names(&mut fs));
+    }
+
+    #[test]
+    fn test_lookup_path() {
+        let dir = TempDir::new().unwrap();
+        create_dir(dir.path().join("album")).unwrap();
+        File::create(dir.path().join("album/01 - a.flac")).unwrap();
+        let mut fs = Mp3V0Fs::new(dir.path().as_os_str().to_os_string(), Options::default());
+
+        // Names are found without a readdir first
+        assert_eq!(Some(PathBuf::from("/album")), fs.lookup_path(Path::new("/"), OsStr::new("album")));
+        assert_eq!(
+            Some(PathBuf::from("/album/01 - a.mp3")),
+            fs.lookup_path(Path::new("/album"), OsStr::new("01 - a.mp3"))
+        );
+        assert_eq!(None, fs.lookup_path(Path::new("/album"), OsStr::new("01 - a.flac")));
+        assert_eq!(None, fs.lookup_path(Path::new("/album"), OsStr::new("missing.mp3")));
+    }
 }
@@ -0,0 +1,275 @@
+use crate::replaygain::ReplayGainMode;
+use crate::sanitize::SanitizeMode;
+use crate::tags::TagMapping;
+use crate::template::NameTemplate;
+use std::path::{Path, PathBuf};
+
+/// Default ISO-639-2 language code used for COMM and USLT frames.
+const DEFAULT_LANGUAGE: &'static str = "eng";
+
+/// Which file is presented when a FLAC and an MP3 in the same directory share a name, e.g.
+/// `song.flac` and `song.mp3`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CollisionPolicy {
+    /// Only the transcoded FLAC is presented
+    Transcode,
+    /// Only the original MP3 is presented
+    Original,
+    /// Both are presented, the transcoded FLAC with a numbered name such as `song (2).mp3`
+    Both
+}
+
+/// How symlinks in the source directory are presented.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SymlinkPolicy {
+    /// Symlinks are presented as symlinks, with links to FLACs pointing at their MP3s
+    Preserve,
+    /// Symlinks are presented as the files and directories they point at. Links that are dangling,
+    /// loop or point outside the source directory are left out
+    Follow
+}
+
+/// Mount options controlling how the source tree is presented and transcoded.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// ISO-639-2 language code written to comment and lyrics frames.
+    pub comment_language: String,
+    /// Whether .lrc sidecars next to a FLAC are hidden from directory listings.
+    pub hide_lrc: bool,
+    /// Vorbis comment to ID3 mapping, optionally extended by a user-defined mapping file.
+    pub tag_mapping: TagMapping,
+    /// Whether ReplayGain is additionally written as RVA2 frames, which switches tags to ID3v2.4.
+    pub rva2: bool,
+    /// ReplayGain applied to the PCM data before encoding.
+    pub replaygain: ReplayGainMode,
+    /// Whether ReplayGain values are written to the transcoded file. Should usually be disabled
+    /// when `replaygain` bakes the gain into the audio.
+    pub replaygain_tags: bool,
+    /// Whether track gain and peak are measured while encoding files without ReplayGain tags.
+    /// Measured values are only written as tags, they can't be applied by `replaygain`.
+    pub analyze_loudness: bool,
+    /// Database of ReplayGain values computed by a background scan of the source directory.
+    pub loudness_db: Option<PathBuf>,
+    /// Whether an iTunSMPB comment is written so Apple players play the transcodes gaplessly.
+    pub itunsmpb: bool,
+    /// Whether FLACs with a .cue sidecar are presented as one virtual MP3 per track instead of a
+    /// single MP3 of the whole album.
+    pub split_cue: bool,
+    /// Whether every directory of FLACs gets a virtual gapless MP3 of all its tracks, with a
+    /// chapter per track.
+    pub album_file: bool,
+    /// Whether the virtual `/.by-artist`, `/.by-genre` and `/.by-year` directories are shown. They
+    /// are built by a background scan of the source directory at mount time.
+    pub views: bool,
+    /// Template the names of transcoded FLACs are built from, instead of their source names.
+    pub name_template: Option<NameTemplate>,
+    /// How names are rewritten so they can be copied to other filesystems.
+    pub sanitize: SanitizeMode,
+    /// Which file is presented when a FLAC and an MP3 share a name.
+    pub collision: CollisionPolicy,
+    /// How symlinks in the source directory are presented.
+    pub symlinks: SymlinkPolicy
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            comment_language: String::from(DEFAULT_LANGUAGE),
+            hide_lrc: false,
+            tag_mapping: TagMapping::default(),
+            rva2: false,
+            replaygain: ReplayGainMode::Off,
+            replaygain_tags: true,
+            analyze_loudness: false,
+            loudness_db: None,
+            itunsmpb: false,
+            split_cue: true,
+            album_file: false,
+            views: false,
+            name_template: None,
+            sanitize: SanitizeMode::None,
+            collision: CollisionPolicy::Original,
+            symlinks: SymlinkPolicy::Preserve
+        }
+    }
+}
+
+impl Options {
+
+    /// Parses a comma separated list of `key=value` options, e.g. the argument to `-o`. Commas
+    /// within a value, e.g. a `tag_mapping` path or a `name_template`, are escaped as `\,` and
+    /// backslashes as `\\`.
+    pub fn parse(&mut self, options: &str) -> Result<(), String> {
+        for option in split_options(options).iter().filter(|option| !option.is_empty()) {
+            let (key, value) = match option.find("=") {
+                Some(index) => (&option[..index], Some(&option[index + 1..])),
+                None => (option, None)
+            };
+            self.set(key, value)?;
+        }
+
+        Ok(())
+    }
+
+    fn set(&mut self, key: &str, value: Option<&str>) -> Result<(), String> {
+        match key {
+            "comment_language" => {
+                let value = required_value(key, value)?;
+                if value.len() != 3 || !value.chars().all(|c| c.is_ascii_alphabetic()) {
+                    return Err(format!("{} must be a 3 letter ISO-639-2 code, got {}", key, value));
+                }
+                self.comment_language = value.to_lowercase();
+            },
+            "hide_lrc" => self.hide_lrc = bool_value(key, value)?,
+            "rva2" => self.rva2 = bool_value(key, value)?,
+            "replaygain" => {
+                self.replaygain = match required_value(key, value)? {
+                    "off" => ReplayGainMode::Off,
+                    "track" => ReplayGainMode::Track,
+                    "album" => ReplayGainMode::Album,
+                    value => return Err(format!("{} must be one of off, track or album, got {}", key, value))
+                };
+            },
+            "replaygain_tags" => self.replaygain_tags = bool_value(key, value)?,
+            "analyze_loudness" => self.analyze_loudness = bool_value(key, value)?,
+            "itunsmpb" => self.itunsmpb = bool_value(key, value)?,
+            "split_cue" => self.split_cue = bool_value(key, value)?,
+            "album_file" => self.album_file = bool_value(key, value)?,
+            "views" => self.views = bool_value(key, value)?,
+            "sanitize" => {
+                self.sanitize = match required_value(key, value)? {
+                    "none" => SanitizeMode::None,
+                    "vfat" => SanitizeMode::Vfat,
+                    value => return Err(format!("{} must be one of none or vfat, got {}", key, value))
+                };
+            },
+            "collision" => {
+                self.collision = match required_value(key, value)? {
+                    "transcode" => CollisionPolicy::Transcode,
+                    "original" => CollisionPolicy::Original,
+                    "both" => CollisionPolicy::Both,
+                    value => return Err(format!("{} must be one of transcode, original or both, got {}", key, value))
+                };
+            },
+            "symlinks" => {
+                self.symlinks = match required_value(key, value)? {
+                    "preserve" => SymlinkPolicy::Preserve,
+                    "follow" => SymlinkPolicy::Follow,
+                    value => return Err(format!("{} must be one of preserve or follow, got {}", key, value))
+                };
+            },
+            "name_template" => self.name_template = Some(NameTemplate::parse(required_value(key, value)?)?),
+            "loudness_db" => self.loudness_db = Some(PathBuf::from(required_value(key, value)?)),
+            "tag_mapping" => self.tag_mapping = TagMapping::load(Path::new(required_value(key, value)?))?,
+            _ => return Err(format!("Unknown option {}", key))
+        }
+
+        Ok(())
+    }
+}
+
+fn required_value<'a>(key: &str, value: Option<&'a str>) -> Result<&'a str, String> {
+    match value {
+        Some(value) if !value.is_empty() => Ok(value),
+        _ => Err(format!("Option {} requires a value", key))
+    }
+}
+
+/// Flags may be given bare (`hide_lrc`) or with an explicit value (`hide_lrc=false`).
+fn bool_value(key: &str, value: Option<&str>) -> Result<bool, String> {
+    match value {
+        None | Some("true") | Some("1") => Ok(true),
+        Some("false") | Some("0") => Ok(false),
+        Some(value) => Err(format!("Option {} expects true or false, got {}", key, value))
+    }
+}
+
+/// Splits a list of options on the commas that aren't escaped, unescaping `\,` and `\\`. Other
+/// backslashes are kept as they are.
+fn split_options(options: &str) -> Vec<String> {
+    let mut split = vec![String::new()];
+    let mut chars = options.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&',') || chars.peek() == Some(&'\\') => {
+                split.last_mut().unwrap().push(chars.next().unwrap());
+            },
+            ',' => split.push(String::new()),
+            c => split.last_mut().unwrap().push(c)
+        }
+    }
+    split
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::options::{CollisionPolicy, Options, SymlinkPolicy, split_options};
+    use crate::replaygain::ReplayGainMode;
+    use crate::sanitize::SanitizeMode;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_parse() {
+        let mut options = Options::default();
+        assert_eq!("eng", options.comment_language);
+
+        options.parse("comment_language=DEU").unwrap();
+        assert_eq!("deu", options.comment_language);
+
+        assert_eq!(false, options.hide_lrc);
+        options.parse("hide_lrc").unwrap();
+        assert_eq!(true, options.hide_lrc);
+        options.parse("hide_lrc=false,comment_language=fra").unwrap();
+        assert_eq!(false, options.hide_lrc);
+        assert_eq!("fra", options.comment_language);
+
+        options.parse("replaygain=album,replaygain_tags=false").unwrap();
+        assert_eq!(ReplayGainMode::Album, options.replaygain);
+        assert_eq!(false, options.replaygain_tags);
+
+        assert_eq!(true, options.split_cue);
+        options.parse("split_cue=0").unwrap();
+        assert_eq!(false, options.split_cue);
+
+        options.parse("name_template={tracknumber:02} {title}.mp3").unwrap();
+        assert!(options.name_template.is_some());
+
+        assert_eq!(SanitizeMode::None, options.sanitize);
+        options.parse("sanitize=vfat").unwrap();
+        assert_eq!(SanitizeMode::Vfat, options.sanitize);
+
+        assert_eq!(CollisionPolicy::Original, options.collision);
+        options.parse("collision=both").unwrap();
+        assert_eq!(CollisionPolicy::Both, options.collision);
+
+        assert_eq!(SymlinkPolicy::Preserve, options.symlinks);
+        options.parse("symlinks=follow").unwrap();
+        assert_eq!(SymlinkPolicy::Follow, options.symlinks);
+
+        assert!(options.parse("replaygain=loud").is_err());
+        assert!(options.parse("hide_lrc=maybe").is_err());
+        assert!(options.parse("comment_language=english").is_err());
+        assert!(options.parse("comment_language").is_err());
+        assert!(options.parse("name_template={title").is_err());
+        assert!(options.parse("sanitize=ntfs").is_err());
+        assert!(options.parse("collision=newest").is_err());
+        assert!(options.parse("symlinks").is_err());
+        assert!(options.parse("not_an_option=1").is_err());
+    }
+
+    #[test]
+    fn test_escaped_commas() {
+        let mut options = Options::default();
+        options.parse("loudness_db=/data/loudness\\,v2.db,hide_lrc").unwrap();
+        assert_eq!(Some(PathBuf::from("/data/loudness,v2.db")), options.loudness_db);
+        assert_eq!(true, options.hide_lrc);
+
+        options.parse("name_template={artist}\\, {title}.mp3").unwrap();
+        assert!(options.name_template.is_some());
+
+        assert_eq!(vec!["a,b", "c"], split_options("a\\,b,c"));
+        assert_eq!(vec!["a\\", "b"], split_options("a\\\\,b"));
+        assert_eq!(vec!["C:\\music"], split_options("C:\\music"));
+        assert_eq!(vec![""], split_options(""));
+    }
+}
@@ -0,0 +1,243 @@
+pub const TRACK_GAIN: &'static str = "REPLAYGAIN_TRACK_GAIN";
+pub const TRACK_PEAK: &'static str = "REPLAYGAIN_TRACK_PEAK";
+pub const ALBUM_GAIN: &'static str = "REPLAYGAIN_ALBUM_GAIN";
+pub const ALBUM_PEAK: &'static str = "REPLAYGAIN_ALBUM_PEAK";
+
+/// Which ReplayGain value, if any, is applied to the PCM data before encoding.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReplayGainMode {
+    Off,
+    Track,
+    Album
+}
+
+/// Returns true if the vorbis comment holds a ReplayGain value.
+pub fn is_replaygain_comment(name: &str) -> bool {
+    match name.to_uppercase().as_ref() {
+        TRACK_GAIN | TRACK_PEAK | ALBUM_GAIN | ALBUM_PEAK => true,
+        _ => false
+    }
+}
+
+/// ReplayGain values of a track, gains in dB and peaks relative to full scale.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReplayGain {
+    pub track_gain: Option<f64>,
+    pub track_peak: Option<f64>,
+    pub album_gain: Option<f64>,
+    pub album_peak: Option<f64>
+}
+
+impl ReplayGain {
+
+    /// Collects ReplayGain values from vorbis comments. Unparseable values are ignored.
+    pub fn from_vorbis_comments<'a, I>(comments: I) -> ReplayGain
+        where I: Iterator<Item = (&'a str, &'a str)> {
+        let mut replay_gain = ReplayGain::default();
+
+        for (name, value) in comments {
+            match name.to_uppercase().as_ref() {
+                TRACK_GAIN => replay_gain.track_gain = parse_gain(value),
+                TRACK_PEAK => replay_gain.track_peak = parse_peak(value),
+                ALBUM_GAIN => replay_gain.album_gain = parse_gain(value),
+                ALBUM_PEAK => replay_gain.album_peak = parse_peak(value),
+                _ => ()
+            }
+        }
+
+        replay_gain
+    }
+
+    /// Fills in the values missing from these ReplayGain values with those of `other`.
+    pub fn or(self, other: &ReplayGain) -> ReplayGain {
+        ReplayGain {
+            track_gain: self.track_gain.or(other.track_gain),
+            track_peak: self.track_peak.or(other.track_peak),
+            album_gain: self.album_gain.or(other.album_gain),
+            album_peak: self.album_peak.or(other.album_peak)
+        }
+    }
+
+    /// Returns the factor samples should be scaled by to apply the gain selected by `mode`, falling
+    /// back to the other gain if the selected one is missing. The factor is limited so the peak
+    /// sample doesn't clip.
+    pub fn scale_factor(&self, mode: ReplayGainMode) -> f64 {
+        let (gain, peak) = match mode {
+            ReplayGainMode::Off => return 1.0,
+            ReplayGainMode::Track => (
+                self.track_gain.or(self.album_gain), self.track_peak.or(self.album_peak)
+            ),
+            ReplayGainMode::Album => (
+                self.album_gain.or(self.track_gain), self.album_peak.or(self.track_peak)
+            )
+        };
+
+        let scale = match gain {
+            Some(gain) => 10f64.powf(gain / 20.0),
+            None => return 1.0
+        };
+        match peak {
+            Some(peak) if peak > 0.0 && peak * scale > 1.0 => 1.0 / peak,
+            _ => scale
+        }
+    }
+}
+
+/// Scales a 16-bit sample, clamping the result to the 16-bit range.
+pub fn apply_scale(sample: i32, scale: f64) -> i16 {
+    if scale == 1.0 {
+        return sample as i16;
+    }
+
+    (f64::from(sample) * scale).round()
+        .max(f64::from(i16::min_value()))
+        .min(f64::from(i16::max_value())) as i16
+}
+
+/// Parses a gain value such as "-7.45 dB".
+pub fn parse_gain(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let value = match value.get(value.len().saturating_sub(2)..) {
+        Some(unit) if unit.eq_ignore_ascii_case("db") => &value[..value.len() - 2],
+        _ => value
+    };
+    value.trim().parse().ok()
+}
+
+/// Parses a peak value such as "0.987654".
+pub fn parse_peak(value: &str) -> Option<f64> {
+    match value.trim().parse::<f64>() {
+        Ok(peak) if peak >= 0.0 => Some(peak),
+        _ => None
+    }
+}
+
+/// Formats a gain the way ReplayGain scanners write it, e.g. "-7.45 dB".
+pub fn format_gain(gain: f64) -> String {
+    format!("{:+.2} dB", gain)
+}
+
+/// Placeholder values reserving space in the tag for gains measured during encoding. Values
+/// formatted with the fixed width functions below have the same length.
+pub const GAIN_PLACEHOLDER: &'static str = "+00.00 dB";
+pub const PEAK_PLACEHOLDER: &'static str = "0.000000";
+
+/// Formats a gain with a fixed width of 9 characters, e.g. "-07.45 dB".
+pub fn format_gain_fixed_width(gain: f64) -> String {
+    format!("{:+06.2} dB", gain.max(-99.99).min(99.99))
+}
+
+/// Formats a peak with a fixed width of 8 characters, e.g. "0.987654".
+pub fn format_peak_fixed_width(peak: f64) -> String {
+    format!("{:.6}", peak.max(0.0).min(9.999999))
+}
+
+/// Formats a peak the way ReplayGain scanners write it, e.g. "0.987654".
+pub fn format_peak(peak: f64) -> String {
+    format!("{:.6}", peak)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::replaygain::{
+        GAIN_PLACEHOLDER, PEAK_PLACEHOLDER, ReplayGain, ReplayGainMode, apply_scale, format_gain,
+        format_gain_fixed_width, format_peak_fixed_width, is_replaygain_comment, parse_gain, parse_peak
+    };
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Some(-7.45), parse_gain("-7.45 dB"));
+        assert_eq!(Some(3.1), parse_gain("+3.10 dB"));
+        assert_eq!(Some(1.0), parse_gain("1.0"));
+        assert_eq!(None, parse_gain("loud"));
+        assert_eq!(Some(0.987654), parse_peak("0.987654"));
+        assert_eq!(None, parse_peak("-1"));
+        assert_eq!("-7.45 dB", format_gain(-7.45));
+        assert_eq!("+3.10 dB", format_gain(3.1));
+
+        assert_eq!("-07.45 dB", format_gain_fixed_width(-7.45));
+        assert_eq!("+12.00 dB", format_gain_fixed_width(12.0));
+        assert_eq!("-99.99 dB", format_gain_fixed_width(-150.0));
+        assert_eq!(GAIN_PLACEHOLDER.len(), format_gain_fixed_width(3.0).len());
+        assert_eq!(Some(-7.45), parse_gain(&format_gain_fixed_width(-7.45)));
+        assert_eq!("0.987654", format_peak_fixed_width(0.987654));
+        assert_eq!(PEAK_PLACEHOLDER.len(), format_peak_fixed_width(1.0).len());
+    }
+
+    #[test]
+    fn test_from_vorbis_comments() {
+        let comments = vec![
+            ("replaygain_track_gain", "-7.45 dB"),
+            ("REPLAYGAIN_TRACK_PEAK", "0.987654"),
+            ("REPLAYGAIN_ALBUM_GAIN", "-6.00 dB"),
+            ("ARTIST", "test_artist"),
+        ];
+
+        let expected = ReplayGain {
+            track_gain: Some(-7.45),
+            track_peak: Some(0.987654),
+            album_gain: Some(-6.0),
+            album_peak: None
+        };
+        assert_eq!(expected, ReplayGain::from_vorbis_comments(comments.into_iter()));
+        assert!(is_replaygain_comment("replaygain_album_peak"));
+        assert!(!is_replaygain_comment("ARTIST"));
+    }
+
+    #[test]
+    fn test_or() {
+        let tagged = ReplayGain {
+            track_gain: Some(-7.45),
+            track_peak: Some(0.9),
+            album_gain: None,
+            album_peak: None
+        };
+        let stored = ReplayGain {
+            track_gain: Some(-7.0),
+            track_peak: Some(0.8),
+            album_gain: Some(-6.0),
+            album_peak: Some(0.95)
+        };
+
+        let expected = ReplayGain {
+            track_gain: Some(-7.45),
+            track_peak: Some(0.9),
+            album_gain: Some(-6.0),
+            album_peak: Some(0.95)
+        };
+        assert_eq!(expected, tagged.or(&stored));
+    }
+
+    #[test]
+    fn test_scale_factor() {
+        let replay_gain = ReplayGain {
+            track_gain: Some(-6.0),
+            track_peak: Some(0.9),
+            album_gain: Some(6.0),
+            album_peak: Some(0.9)
+        };
+
+        assert_eq!(1.0, replay_gain.scale_factor(ReplayGainMode::Off));
+        assert!((replay_gain.scale_factor(ReplayGainMode::Track) - 0.501187).abs() < 0.000001);
+        // +6 dB would clip the 0.9 peak, so the gain is limited to bring the peak to full scale
+        assert!((replay_gain.scale_factor(ReplayGainMode::Album) - 1.0 / 0.9).abs() < 0.000001);
+
+        // Falls back to the track gain when there's no album gain
+        let replay_gain = ReplayGain {
+            track_gain: Some(-6.0),
+            track_peak: None,
+            album_gain: None,
+            album_peak: None
+        };
+        assert!((replay_gain.scale_factor(ReplayGainMode::Album) - 0.501187).abs() < 0.000001);
+        assert_eq!(1.0, ReplayGain::default().scale_factor(ReplayGainMode::Track));
+    }
+
+    #[test]
+    fn test_apply_scale() {
+        assert_eq!(1000, apply_scale(1000, 1.0));
+        assert_eq!(500, apply_scale(1000, 0.5));
+        assert_eq!(32767, apply_scale(30000, 2.0));
+        assert_eq!(-32768, apply_scale(-30000, 2.0));
+    }
+}
@@ -0,0 +1,152 @@
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+/// Longest file name, in bytes, most filesystems accept.
+pub const MAX_NAME_BYTES: usize = 255;
+// Characters VFAT doesn't allow in file names, on top of control characters
+const VFAT_RESERVED: &'static str = "\"*/:<>?\\|";
+
+/// How names are rewritten before being presented under the mountpoint.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SanitizeMode {
+    /// Names are presented as they are
+    None,
+    /// Names can be copied to FAT formatted media: reserved characters are replaced, trailing dots
+    /// and spaces are removed and names are limited to 255 bytes
+    Vfat
+}
+
+impl SanitizeMode {
+
+    /// Returns the name as presented under the mountpoint.
+    pub fn apply(self, name: &str) -> String {
+        match self {
+            SanitizeMode::None => String::from(name),
+            SanitizeMode::Vfat => vfat_name(name)
+        }
+    }
+
+    /// Returns the name as presented under the mountpoint. Names that aren't valid UTF-8 are kept
+    /// as they are unless sanitized, in which case invalid sequences are replaced.
+    pub fn apply_os(self, name: &OsStr) -> OsString {
+        match self {
+            SanitizeMode::None => name.to_os_string(),
+            SanitizeMode::Vfat => OsString::from(vfat_name(&name.to_string_lossy()))
+        }
+    }
+
+    /// Returns the key two names collide on within a directory. VFAT is case insensitive.
+    pub fn collision_key(self, name: &OsStr) -> OsString {
+        match self {
+            SanitizeMode::None => name.to_os_string(),
+            SanitizeMode::Vfat => OsString::from(name.to_string_lossy().to_lowercase())
+        }
+    }
+}
+
+fn vfat_name(name: &str) -> String {
+    let name: String = name.chars()
+        .map(|c| if (c as u32) < 0x20 || VFAT_RESERVED.contains(c) { '_' } else { c })
+        .collect();
+    let name = name.trim_start_matches(' ').trim_end_matches(|c| c == '.' || c == ' ');
+
+    match name {
+        "" => String::from("_"),
+        name => {
+            let (stem, extension) = split_extension(name);
+            limit_length(stem, extension)
+        }
+    }
+}
+
+/// Adds a counter to a name to tell it apart from others, e.g. "name (2).mp3". Names are handled
+/// as bytes, so names that aren't valid UTF-8 keep their bytes.
+pub fn numbered(name: &OsStr, counter: u32) -> OsString {
+    let name = name.as_bytes();
+    let (stem, extension) = match name.iter().rposition(|&byte| byte == b'.') {
+        Some(index) if index > 0 => (&name[..index], &name[index..]),
+        _ => (name, &b""[..])
+    };
+    let mut suffix = format!(" ({})", counter).into_bytes();
+    suffix.extend_from_slice(extension);
+
+    // Truncated to fit, without cutting UTF-8 encoded characters in half
+    let mut length = MAX_NAME_BYTES.saturating_sub(suffix.len()).min(stem.len());
+    while length < stem.len() && length > 0 && stem[length] & 0xc0 == 0x80 {
+        length -= 1;
+    }
+    let mut numbered = stem[..length].to_vec();
+    while numbered.last().map_or(false, |&byte| byte == b'.' || byte == b' ') {
+        numbered.pop();
+    }
+    numbered.extend_from_slice(&suffix);
+    OsString::from_vec(numbered)
+}
+
+/// Truncates `stem` so that it followed by `suffix` fits in [`MAX_NAME_BYTES`].
+fn limit_length(stem: &str, suffix: &str) -> String {
+    let mut length = MAX_NAME_BYTES.saturating_sub(suffix.len()).min(stem.len());
+    while !stem.is_char_boundary(length) {
+        length -= 1;
+    }
+    format!("{}{}", stem[..length].trim_end_matches(|c| c == '.' || c == ' '), suffix)
+}
+
+/// Splits a name into its stem and extension, including the dot.
+fn split_extension(name: &str) -> (&str, &str) {
+    match name.rfind(".") {
+        Some(index) if index > 0 => (&name[..index], &name[index..]),
+        _ => (name, "")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sanitize::{MAX_NAME_BYTES, SanitizeMode, numbered};
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    #[test]
+    fn test_vfat() {
+        let vfat = SanitizeMode::Vfat;
+        assert_eq!("What_ Is _Love_.mp3", vfat.apply("What? Is \"Love\".mp3"));
+        assert_eq!("AC_DC", vfat.apply("AC:DC..."));
+        assert_eq!("_", vfat.apply(" .."));
+        assert_eq!("tab_name.mp3", vfat.apply("tab\tname.mp3"));
+        assert_eq!("Sigur Rós.mp3", vfat.apply("Sigur Rós.mp3"));
+        assert_eq!("What? Is Love", SanitizeMode::None.apply("What? Is Love"));
+
+        // Long names keep their extension and aren't cut in the middle of a character
+        let long_name = format!("{}.mp3", "é".repeat(200));
+        let sanitized = vfat.apply(&long_name);
+        assert!(sanitized.len() <= MAX_NAME_BYTES);
+        assert!(sanitized.ends_with("é.mp3"));
+
+        assert_eq!("a.mp3", vfat.collision_key(OsStr::new("A.MP3")));
+        assert_eq!("A.MP3", SanitizeMode::None.collision_key(OsStr::new("A.MP3")));
+
+        // Latin-1 names are kept byte for byte unless sanitized
+        let latin1_name = OsStr::from_bytes(b"Bj\xf6rk.mp3");
+        assert_eq!(latin1_name, SanitizeMode::None.apply_os(latin1_name));
+        assert_eq!("Bj\u{FFFD}rk.mp3", vfat.apply_os(latin1_name));
+    }
+
+    #[test]
+    fn test_numbered() {
+        assert_eq!("name (2).mp3", numbered(OsStr::new("name.mp3"), 2));
+        assert_eq!("Album (3)", numbered(OsStr::new("Album"), 3));
+        assert_eq!(".hidden (2)", numbered(OsStr::new(".hidden"), 2));
+
+        let numbered_name = numbered(OsStr::new(&format!("{}.mp3", "a".repeat(300))), 12);
+        assert_eq!(MAX_NAME_BYTES, numbered_name.len());
+        assert!(numbered_name.to_str().unwrap().ends_with("a (12).mp3"));
+
+        // Long names aren't cut in the middle of a character
+        let numbered_name = numbered(OsStr::new(&format!("{}.mp3", "é".repeat(200))), 2);
+        assert!(numbered_name.len() <= MAX_NAME_BYTES);
+        assert!(numbered_name.to_str().unwrap().ends_with("é (2).mp3"));
+
+        // Latin-1 names keep their bytes
+        assert_eq!(OsStr::from_bytes(b"Bj\xf6rk (2).mp3"), numbered(OsStr::from_bytes(b"Bj\xf6rk.mp3"), 2));
+    }
+}
@@ -1,10 +1,42 @@
-use id3::Frame;
-use id3::frame::Content;
+use id3::{Frame, Tag, Version};
+use id3::frame::{Comment, Content, ExtendedText, Lyrics};
+use crate::lrc::LyricLine;
+use crate::replaygain;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Description of the COMM frame iTunes reads gapless playback information from.
+pub const ITUNSMPB: &'static str = "iTunSMPB";
+// Decoder delay iTunes adds on top of the LAME encoder delay
+const ITUNES_DECODER_DELAY: u32 = 529;
+
+// UFID owner used by MusicBrainz Picard for recording IDs
+const MUSICBRAINZ_UFID_OWNER: &'static str = "http://musicbrainz.org";
+
+// Size of the ID3v2 tag header
+const TAG_HEADER_SIZE: usize = 10;
+// RVA2 channel type marker for the master volume
+const RVA2_CHANNEL_MASTER: u8 = 1;
+// Number of bits used for the RVA2 peak volume
+const RVA2_PEAK_BITS: u8 = 16;
+
+// ID3v2 text encoding marker for UTF-16 with a byte order mark
+const ENCODING_UTF16: u8 = 1;
+// SYLT timestamp format marker for absolute time in milliseconds
+const SYLT_TIMESTAMP_MILLISECONDS: u8 = 2;
+// SYLT content type marker for lyrics
+const SYLT_CONTENT_LYRICS: u8 = 1;
+// CHAP marker for byte offsets that aren't provided, so players go by the times
+const CHAP_NO_OFFSET: u32 = 0xFFFFFFFF;
+// CTOC flags of a top-level table of contents with ordered entries
+const CTOC_TOP_LEVEL_ORDERED: u8 = 0x03;
 
 /// Translates a vorbis comment to the corresponding ID3v2.3 frame.
+/// Comment and lyrics frames are tagged with the provided ISO-639-2 language and an empty description.
 /// Source for the mappings: https://wiki.hydrogenaud.io/index.php?title=Tag_Mapping
 pub fn translate_vorbis_comment_to_id3(
-    vorbis_name: &String, vorbis_value: &String
+    vorbis_name: &String, vorbis_value: &String, language: &str
 ) -> Option<Frame> {
     match vorbis_name.to_uppercase().as_ref() {
         "ALBUM" => Some(Frame::with_content("TALB", Content::Text(vorbis_value.clone()))),
@@ -15,8 +47,30 @@ pub fn translate_vorbis_comment_to_id3(
         "YEAR" => Some(Frame::with_content("TYER", Content::Text(vorbis_value.clone()))),
         "ISRC" => Some(Frame::with_content("TSRC", Content::Text(vorbis_value.clone()))),
         "GENRE" => Some(Frame::with_content("TCON", Content::Text(vorbis_value.clone()))),
-        "COMMENT" => Some(Frame::with_content("COMM", Content::Text(vorbis_value.clone()))),
+        "COMMENT" | "DESCRIPTION" => Some(Frame::with_content("COMM", Content::Comment(Comment {
+            lang: String::from(language),
+            description: String::new(),
+            text: vorbis_value.clone()
+        }))),
+        "LYRICS" | "UNSYNCEDLYRICS" => Some(Frame::with_content("USLT", Content::Lyrics(Lyrics {
+            lang: String::from(language),
+            description: String::new(),
+            text: vorbis_value.clone()
+        }))),
         "COPYRIGHT" => Some(Frame::with_content("TCOP", Content::Text(vorbis_value.clone()))),
+        "ALBUMARTISTSORT" => Some(Frame::with_content("TSO2", Content::Text(vorbis_value.clone()))),
+        "ARTISTSORT" => Some(Frame::with_content("TSOP", Content::Text(vorbis_value.clone()))),
+        "ALBUMSORT" => Some(Frame::with_content("TSOA", Content::Text(vorbis_value.clone()))),
+        "TITLESORT" => Some(Frame::with_content("TSOT", Content::Text(vorbis_value.clone()))),
+        "COMPILATION" => Some(Frame::with_content("TCMP", Content::Text(vorbis_value.clone()))),
+        "MUSICBRAINZ_TRACKID" => Some(unique_file_identifier_frame(MUSICBRAINZ_UFID_OWNER, vorbis_value)),
+        "MUSICBRAINZ_ALBUMID" => Some(extended_text_frame("MusicBrainz Album Id", vorbis_value)),
+        "MUSICBRAINZ_ARTISTID" => Some(extended_text_frame("MusicBrainz Artist Id", vorbis_value)),
+        "MUSICBRAINZ_ALBUMARTISTID" => Some(extended_text_frame("MusicBrainz Album Artist Id", vorbis_value)),
+        "MUSICBRAINZ_RELEASEGROUPID" => Some(extended_text_frame("MusicBrainz Release Group Id", vorbis_value)),
+        // foobar2000 style lowercase descriptions
+        replaygain::TRACK_GAIN | replaygain::TRACK_PEAK | replaygain::ALBUM_GAIN | replaygain::ALBUM_PEAK =>
+            Some(extended_text_frame(&vorbis_name.to_lowercase(), vorbis_value)),
         _ => {
             info!("No corresponding ID3v2.3 tag found for vorbis comment {}, ignoring", vorbis_name);
             None
@@ -24,28 +78,659 @@ pub fn translate_vorbis_comment_to_id3(
     }
 }
 
+/// Describes how a single vorbis comment is written to ID3.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Mapping {
+    /// Written to the frame with the given ID
+    Frame(String),
+    /// Written to a TXXX frame with the given description
+    ExtendedText(String),
+    /// Not written at all
+    Suppressed
+}
+
+/// Vorbis comment to ID3 mapping made of user-defined overrides on top of the built-in table in
+/// [`translate_vorbis_comment_to_id3()`].
+///
+/// Mapping files contain one `VORBISNAME = "TARGET"` entry per line, where the target is a frame ID
+/// (`"TIT1"`), a TXXX frame with a custom description (`"TXXX:Mood"`), or empty to suppress the
+/// comment. Blank lines and lines starting with `#` are ignored.
+#[derive(Clone, Debug, Default)]
+pub struct TagMapping {
+    overrides: HashMap<String, Mapping>
+}
+
+impl TagMapping {
+
+    /// Loads a mapping file from disk.
+    pub fn load(path: &Path) -> Result<TagMapping, String> {
+        match fs::read_to_string(path) {
+            Ok(contents) => TagMapping::parse(&contents),
+            Err(err) => Err(format!("Failed to read tag mapping {}: {}", path.display(), err))
+        }
+    }
+
+    /// Parses the contents of a mapping file.
+    pub fn parse(contents: &str) -> Result<TagMapping, String> {
+        let mut overrides = HashMap::new();
+
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("#") {
+                continue;
+            }
+
+            let separator = match line.find("=") {
+                Some(separator) => separator,
+                None => return Err(format!("Line {} of tag mapping is missing '='", index + 1))
+            };
+            let vorbis_name = line[..separator].trim().trim_matches('"').to_uppercase();
+            let target = line[separator + 1..].trim().trim_matches('"');
+
+            let mapping = match parse_mapping_target(target) {
+                Some(mapping) => mapping,
+                None => return Err(format!(
+                    "Line {} of tag mapping has an unsupported target {}", index + 1, target
+                ))
+            };
+            overrides.insert(vorbis_name, mapping);
+        }
+
+        Ok(TagMapping { overrides })
+    }
+
+    /// Returns whether the mapping file has an entry for the provided vorbis comment.
+    pub fn is_overridden(&self, vorbis_name: &str) -> bool {
+        self.overrides.contains_key(&vorbis_name.to_uppercase())
+    }
+
+    /// Translates a vorbis comment to an ID3 frame, preferring user-defined mappings over the
+    /// built-in ones.
+    pub fn translate(&self, vorbis_name: &String, vorbis_value: &String, language: &str) -> Option<Frame> {
+        match self.overrides.get(&vorbis_name.to_uppercase()) {
+            Some(Mapping::Frame(id)) => Some(frame_with_id(id, vorbis_name, vorbis_value, language)),
+            Some(Mapping::ExtendedText(description)) => Some(extended_text_frame(description, vorbis_value)),
+            Some(Mapping::Suppressed) => None,
+            None => translate_vorbis_comment_to_id3(vorbis_name, vorbis_value, language)
+        }
+    }
+}
+
+fn parse_mapping_target(target: &str) -> Option<Mapping> {
+    if target.is_empty() {
+        return Some(Mapping::Suppressed);
+    }
+    if target.starts_with("TXXX:") {
+        return Some(Mapping::ExtendedText(String::from(&target[5..])));
+    }
+
+    let is_frame_id = target.len() == 4
+        && target.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit());
+    match target {
+        "COMM" | "USLT" | "TXXX" => Some(Mapping::Frame(String::from(target))),
+        _ if is_frame_id && target.starts_with("T") => Some(Mapping::Frame(String::from(target))),
+        _ => None
+    }
+}
+
+/// Builds a frame with the given ID holding a vorbis comment value. A bare TXXX target uses the
+/// vorbis name as its description.
+fn frame_with_id(id: &str, vorbis_name: &String, vorbis_value: &String, language: &str) -> Frame {
+    match id {
+        "COMM" => Frame::with_content("COMM", Content::Comment(Comment {
+            lang: String::from(language),
+            description: String::new(),
+            text: vorbis_value.clone()
+        })),
+        "USLT" => Frame::with_content("USLT", Content::Lyrics(Lyrics {
+            lang: String::from(language),
+            description: String::new(),
+            text: vorbis_value.clone()
+        })),
+        "TXXX" => extended_text_frame(vorbis_name, vorbis_value),
+        _ => Frame::with_content(id, Content::Text(vorbis_value.clone()))
+    }
+}
+
+/// Adds a frame to the tag. `Tag::add_frame` replaces any frame with the same ID, so frames that may
+/// legitimately occur several times (TXXX, COMM) are added through the matching helper instead.
+pub fn add_frame(tag: &mut Tag, frame: Frame) {
+    match frame.content() {
+        Content::ExtendedText(extended_text) => {
+            tag.add_extended_text(extended_text.description.clone(), extended_text.value.clone());
+            return;
+        },
+        Content::Comment(comment) => {
+            tag.add_comment(comment.clone());
+            return;
+        },
+        _ => ()
+    }
+
+    tag.add_frame(frame);
+}
+
+/// Builds a user defined text (TXXX) frame.
+pub fn extended_text_frame(description: &str, value: &String) -> Frame {
+    Frame::with_content("TXXX", Content::ExtendedText(ExtendedText {
+        description: String::from(description),
+        value: value.clone()
+    }))
+}
+
+/// Builds the frames recording how a FLAC was transcoded: TSSE with the encoder settings, TENC and
+/// TXXX frames describing the source stream. An all zero MD5 means the FLAC has none and is skipped.
+pub fn transcode_frames(encoder_settings: &str, bits_per_sample: u32, sample_rate: u32, md5sum: &[u8]) -> Vec<Frame> {
+    let mut frames = vec![
+        Frame::with_content("TSSE", Content::Text(String::from(encoder_settings))),
+        Frame::with_content("TENC", Content::Text(String::from(env!("CARGO_PKG_NAME")))),
+        extended_text_frame("Source format", &String::from("FLAC")),
+        extended_text_frame("Source bit depth", &bits_per_sample.to_string()),
+        extended_text_frame("Source sample rate", &sample_rate.to_string()),
+    ];
+
+    if md5sum.iter().any(|byte| *byte != 0) {
+        let md5: Vec<String> = md5sum.iter().map(|byte| format!("{:02x}", byte)).collect();
+        frames.push(extended_text_frame("Source MD5", &md5.concat()));
+    }
+
+    frames
+}
+
+/// Builds a comment (COMM) frame with a description.
+pub fn comment_frame(description: &str, text: &str, language: &str) -> Frame {
+    Frame::with_content("COMM", Content::Comment(Comment {
+        lang: String::from(language),
+        description: String::from(description),
+        text: String::from(text)
+    }))
+}
+
+/// Builds the iTunSMPB value describing the encoder delay, padding and original length (in
+/// samples) of an MP3 made of `frames` LAME frames of `frame_size` samples each (1152 for MPEG-1,
+/// 576 for MPEG-2 and 2.5). The value always has the same length.
+pub fn itunes_smpb(encoder_delay: u32, frame_size: u32, frames: u32, samples: u64) -> String {
+    let delay = encoder_delay + ITUNES_DECODER_DELAY;
+    let padding = (u64::from(frames) * u64::from(frame_size)).saturating_sub(u64::from(delay) + samples);
+
+    format!(
+        " 00000000 {:08X} {:08X} {:016X} 00000000 00000000 00000000 00000000 00000000 00000000 00000000 00000000",
+        delay, padding, samples
+    )
+}
+
+/// Builds a unique file identifier (UFID) frame. As with SYLT the body is encoded by hand.
+pub fn unique_file_identifier_frame(owner: &str, identifier: &String) -> Frame {
+    let mut data: Vec<u8> = Vec::with_capacity(owner.len() + 1 + identifier.len());
+    data.extend_from_slice(owner.as_bytes());
+    data.push(0);
+    data.extend_from_slice(identifier.as_bytes());
+
+    Frame::with_content("UFID", Content::Unknown(data))
+}
+
+/// Builds the body of a relative volume adjustment (RVA2) frame for the master channel.
+/// `identification` is "track" or "album", as written by most ReplayGain aware taggers.
+pub fn relative_volume_adjustment_body(identification: &str, gain: f64, peak: Option<f64>) -> Vec<u8> {
+    let mut data: Vec<u8> = Vec::new();
+    data.extend_from_slice(identification.as_bytes());
+    data.push(0);
+    data.push(RVA2_CHANNEL_MASTER);
+
+    // Volume adjustment is stored in 1/512 dB steps
+    let adjustment = (gain * 512.0).round().max(i16::min_value() as f64).min(i16::max_value() as f64) as i16;
+    data.extend_from_slice(&adjustment.to_be_bytes());
+
+    match peak {
+        Some(peak) => {
+            data.push(RVA2_PEAK_BITS);
+            let peak = (peak * 32768.0).round().max(0.0).min(u16::max_value() as f64) as u16;
+            data.extend_from_slice(&peak.to_be_bytes());
+        },
+        None => data.push(0)
+    }
+
+    data
+}
+
+/// Encodes a complete frame (header and body) for the given tag version.
+/// Used for frames the id3 crate can't hold several instances of.
+pub fn encode_raw_frame(id: &str, body: &[u8], version: Version) -> Vec<u8> {
+    let mut frame: Vec<u8> = Vec::with_capacity(10 + body.len());
+    frame.extend_from_slice(&id.as_bytes()[..4]);
+    match version {
+        Version::Id3v24 => frame.extend_from_slice(&encode_synchsafe(body.len() as u32)),
+        _ => frame.extend_from_slice(&(body.len() as u32).to_be_bytes())
+    }
+    // No frame flags
+    frame.extend_from_slice(&[0, 0]);
+    frame.extend_from_slice(body);
+    frame
+}
+
+/// A chapter of a file, times in milliseconds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Chapter {
+    pub title: String,
+    pub start: u32,
+    pub end: u32
+}
+
+/// Encodes a CHAP frame per chapter, each titled by a TIT2 subframe, plus a top-level CTOC frame
+/// listing them in order. The result can be passed to [`insert_raw_frames()`].
+pub fn chapter_frames(chapters: &[Chapter], version: Version) -> Vec<u8> {
+    let element_ids: Vec<String> = (0..chapters.len()).map(|index| format!("chp{}", index)).collect();
+
+    let mut table_of_contents: Vec<u8> = Vec::new();
+    table_of_contents.extend_from_slice(b"toc\0");
+    table_of_contents.push(CTOC_TOP_LEVEL_ORDERED);
+    table_of_contents.push(chapters.len().min(u8::max_value() as usize) as u8);
+    for element_id in element_ids.iter().take(u8::max_value() as usize) {
+        table_of_contents.extend_from_slice(element_id.as_bytes());
+        table_of_contents.push(0);
+    }
+    let mut frames = encode_raw_frame("CTOC", &table_of_contents, version);
+
+    for (chapter, element_id) in chapters.iter().zip(element_ids) {
+        let mut title: Vec<u8> = vec![ENCODING_UTF16, 0xFF, 0xFE];
+        title.extend(encode_utf16(&chapter.title));
+
+        let mut body: Vec<u8> = Vec::new();
+        body.extend_from_slice(element_id.as_bytes());
+        body.push(0);
+        body.extend_from_slice(&chapter.start.to_be_bytes());
+        body.extend_from_slice(&chapter.end.to_be_bytes());
+        body.extend_from_slice(&CHAP_NO_OFFSET.to_be_bytes());
+        body.extend_from_slice(&CHAP_NO_OFFSET.to_be_bytes());
+        body.extend(encode_raw_frame("TIT2", &title, version));
+        frames.extend(encode_raw_frame("CHAP", &body, version));
+    }
+
+    frames
+}
+
+/// Inserts already encoded frames into a serialized ID3v2 tag, directly after the tag header, and
+/// updates the tag size accordingly.
+pub fn insert_raw_frames(tag_bytes: &mut Vec<u8>, frames: &[u8]) {
+    if frames.is_empty() || tag_bytes.len() < TAG_HEADER_SIZE {
+        return;
+    }
+
+    let size = decode_synchsafe(&tag_bytes[6..10]) + frames.len() as u32;
+    tag_bytes[6..10].copy_from_slice(&encode_synchsafe(size));
+
+    let tail = tag_bytes.split_off(TAG_HEADER_SIZE);
+    tag_bytes.extend_from_slice(frames);
+    tag_bytes.extend(tail);
+}
+
+fn encode_synchsafe(value: u32) -> [u8; 4] {
+    [
+        ((value >> 21) & 0x7F) as u8,
+        ((value >> 14) & 0x7F) as u8,
+        ((value >> 7) & 0x7F) as u8,
+        (value & 0x7F) as u8
+    ]
+}
+
+fn decode_synchsafe(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |value, byte| (value << 7) | u32::from(byte & 0x7F))
+}
+
+/// Location of a TXXX or COMM value inside a serialized tag, so it can be overwritten once it's known.
+#[derive(Debug, PartialEq)]
+pub struct TextPlaceholder {
+    /// Offset of the value from the start of the tag
+    pub offset: usize,
+    utf16: bool,
+    length: usize
+}
+
+impl TextPlaceholder {
+
+    /// Encodes a replacement value in the encoding of the placeholder. The replacement must be ASCII
+    /// and have the same length as the placeholder.
+    pub fn encode(&self, value: &str) -> Option<Vec<u8>> {
+        if !value.is_ascii() || value.len() != self.length {
+            return None;
+        }
+
+        if self.utf16 {
+            Some(encode_utf16(value))
+        } else {
+            Some(value.as_bytes().to_vec())
+        }
+    }
+}
+
+/// Finds the value of a TXXX or COMM frame written with the given description and ASCII placeholder
+/// value.
+/// Both single byte (ISO-8859-1/UTF-8) and UTF-16 encoded frames are recognized.
+pub fn find_text_placeholder(tag_bytes: &[u8], description: &str, value: &str) -> Option<TextPlaceholder> {
+    let mut single_byte: Vec<u8> = description.as_bytes().to_vec();
+    single_byte.push(0);
+    let value_offset = single_byte.len();
+    single_byte.extend_from_slice(value.as_bytes());
+    if let Some(position) = find_bytes(tag_bytes, &single_byte) {
+        return Some(TextPlaceholder { offset: position + value_offset, utf16: false, length: value.len() });
+    }
+
+    let mut utf16 = encode_utf16_terminated(description);
+    utf16.extend_from_slice(&[0xFF, 0xFE]);
+    let value_offset = utf16.len();
+    utf16.extend(encode_utf16(value));
+    if let Some(position) = find_bytes(tag_bytes, &utf16) {
+        return Some(TextPlaceholder { offset: position + value_offset, utf16: true, length: value.len() });
+    }
+
+    None
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+/// Builds a SYLT frame carrying the provided synchronized lyrics.
+/// The id3 crate has no structured SYLT support, so the frame body is encoded by hand.
+pub fn synchronised_lyrics_frame(lines: &[LyricLine], language: &str) -> Frame {
+    let mut data: Vec<u8> = Vec::new();
+    data.push(ENCODING_UTF16);
+    data.extend_from_slice(&language.as_bytes()[..3]);
+    data.push(SYLT_TIMESTAMP_MILLISECONDS);
+    data.push(SYLT_CONTENT_LYRICS);
+    // Empty content descriptor
+    data.extend(encode_utf16_terminated(""));
+
+    for line in lines {
+        data.extend(encode_utf16_terminated(&line.text));
+        data.extend_from_slice(&line.timestamp.to_be_bytes());
+    }
+
+    Frame::with_content("SYLT", Content::Unknown(data))
+}
+
+/// Builds an unsynchronised lyrics (USLT) frame from the text of the provided synchronized lyrics.
+pub fn unsynchronised_lyrics_frame(lines: &[LyricLine], language: &str) -> Frame {
+    let text: Vec<&str> = lines.iter().map(|line| line.text.as_ref()).collect();
+
+    Frame::with_content("USLT", Content::Lyrics(Lyrics {
+        lang: String::from(language),
+        description: String::new(),
+        text: text.join("\n")
+    }))
+}
+
+/// Encodes a string as null terminated little endian UTF-16 with a byte order mark.
+fn encode_utf16_terminated(text: &str) -> Vec<u8> {
+    let mut encoded = vec![0xFF, 0xFE];
+    encoded.extend(encode_utf16(text));
+    encoded.extend_from_slice(&[0, 0]);
+    encoded
+}
+
+/// Encodes a string as little endian UTF-16.
+fn encode_utf16(text: &str) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(text.len() * 2);
+    for unit in text.encode_utf16() {
+        encoded.extend_from_slice(&unit.to_le_bytes());
+    }
+    encoded
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::tags::translate_vorbis_comment_to_id3;
+    use crate::lrc::LyricLine;
+    use crate::tags::{
+        Chapter, TagMapping, chapter_frames, encode_raw_frame, find_text_placeholder, insert_raw_frames, itunes_smpb,
+        relative_volume_adjustment_body, synchronised_lyrics_frame, transcode_frames,
+        translate_vorbis_comment_to_id3
+    };
+
+    use id3::Version;
 
     use id3::Frame;
-    use id3::frame::Content;
+    use id3::frame::{Comment, Content, ExtendedText, Lyrics};
 
    #[test]
    fn test_translate_vorbis_comment_to_id3() {
        // Tag with only ASCII characters in the value
        let expected = Some(Frame::with_content("TALB", Content::Text(String::from("Polychrome"))));
-       let actual = translate_vorbis_comment_to_id3(&String::from("Album"), &String::from("Polychrome"));
+       let actual = translate_vorbis_comment_to_id3(&String::from("Album"), &String::from("Polychrome"), "eng");
        assert_eq!(expected, actual);
 
        // Tag with non-ASCII characters in the value
        let expected = Some(Frame::with_content("TALB", Content::Text(String::from("नमस्ते"))));
-       let actual = translate_vorbis_comment_to_id3(&String::from("Album"), &String::from("नमस्ते"));
+       let actual = translate_vorbis_comment_to_id3(&String::from("Album"), &String::from("नमस्ते"), "eng");
        assert_eq!(expected, actual);
 
        // Tag with no mapping
        let expected = None;
-       let actual = translate_vorbis_comment_to_id3(&String::from("Not a vorbis comment"), &String::from(""));
+       let actual = translate_vorbis_comment_to_id3(&String::from("Not a vorbis comment"), &String::from(""), "eng");
        assert_eq!(expected, actual);
+
+       // Comments and lyrics carry a language and an empty description
+       let expected = Some(Frame::with_content("COMM", Content::Comment(Comment {
+           lang: String::from("deu"),
+           description: String::new(),
+           text: String::from("Remastered")
+       })));
+       let actual = translate_vorbis_comment_to_id3(&String::from("Description"), &String::from("Remastered"), "deu");
+       assert_eq!(expected, actual);
+
+       let expected = Some(Frame::with_content("USLT", Content::Lyrics(Lyrics {
+           lang: String::from("eng"),
+           description: String::new(),
+           text: String::from("la la la")
+       })));
+       let actual = translate_vorbis_comment_to_id3(&String::from("UNSYNCEDLYRICS"), &String::from("la la la"), "eng");
+       assert_eq!(expected, actual);
+   }
+
+   #[test]
+   fn test_translate_picard_vorbis_comments_to_id3() {
+       let expected = Some(Frame::with_content("TSO2", Content::Text(String::from("Beatles, The"))));
+       let actual = translate_vorbis_comment_to_id3(&String::from("albumartistsort"), &String::from("Beatles, The"), "eng");
+       assert_eq!(expected, actual);
+
+       let expected = Some(Frame::with_content("TCMP", Content::Text(String::from("1"))));
+       let actual = translate_vorbis_comment_to_id3(&String::from("COMPILATION"), &String::from("1"), "eng");
+       assert_eq!(expected, actual);
+
+       let mut ufid = b"http://musicbrainz.org".to_vec();
+       ufid.push(0);
+       ufid.extend_from_slice(b"c0ffee");
+       let expected = Some(Frame::with_content("UFID", Content::Unknown(ufid)));
+       let actual = translate_vorbis_comment_to_id3(&String::from("MUSICBRAINZ_TRACKID"), &String::from("c0ffee"), "eng");
+       assert_eq!(expected, actual);
+
+       let expected = Some(Frame::with_content("TXXX", Content::ExtendedText(ExtendedText {
+           description: String::from("MusicBrainz Release Group Id"),
+           value: String::from("c0ffee")
+       })));
+       let actual = translate_vorbis_comment_to_id3(&String::from("MUSICBRAINZ_RELEASEGROUPID"), &String::from("c0ffee"), "eng");
+       assert_eq!(expected, actual);
+   }
+
+   #[test]
+   fn test_tag_mapping() {
+       let mapping = TagMapping::parse("\
+           # Overrides for our library\n\
+           GROUPING = \"TIT1\"\n\
+           mood = \"TXXX:Mood\"\n\
+           \n\
+           Genre = \"\"\n\
+           LABEL = TXXX\n\
+       ").unwrap();
+
+       let expected = Some(Frame::with_content("TIT1", Content::Text(String::from("Live"))));
+       let actual = mapping.translate(&String::from("grouping"), &String::from("Live"), "eng");
+       assert_eq!(expected, actual);
+
+       let expected = Some(Frame::with_content("TXXX", Content::ExtendedText(ExtendedText {
+           description: String::from("Mood"),
+           value: String::from("Mellow")
+       })));
+       let actual = mapping.translate(&String::from("MOOD"), &String::from("Mellow"), "eng");
+       assert_eq!(expected, actual);
+
+       let expected = Some(Frame::with_content("TXXX", Content::ExtendedText(ExtendedText {
+           description: String::from("LABEL"),
+           value: String::from("Warp")
+       })));
+       let actual = mapping.translate(&String::from("LABEL"), &String::from("Warp"), "eng");
+       assert_eq!(expected, actual);
+
+       // Suppressed mapping
+       let actual = mapping.translate(&String::from("GENRE"), &String::from("Electronic"), "eng");
+       assert_eq!(None, actual);
+
+       // Falls back to the built-in mapping
+       let expected = Some(Frame::with_content("TALB", Content::Text(String::from("Polychrome"))));
+       let actual = mapping.translate(&String::from("ALBUM"), &String::from("Polychrome"), "eng");
+       assert_eq!(expected, actual);
+       assert!(mapping.is_overridden("genre"));
+       assert!(!mapping.is_overridden("ALBUM"));
+
+       // ReplayGain comments can be remapped like any other
+       let mapping = TagMapping::parse("REPLAYGAIN_TRACK_GAIN = \"TXXX:REPLAYGAIN_TRACK_GAIN\"").unwrap();
+       let expected = Some(Frame::with_content("TXXX", Content::ExtendedText(ExtendedText {
+           description: String::from("REPLAYGAIN_TRACK_GAIN"),
+           value: String::from("-6.50 dB")
+       })));
+       let actual = mapping.translate(&String::from("REPLAYGAIN_TRACK_GAIN"), &String::from("-6.50 dB"), "eng");
+       assert_eq!(expected, actual);
+
+       assert!(TagMapping::parse("GROUPING").is_err());
+       assert!(TagMapping::parse("GROUPING = \"APIC\"").is_err());
+   }
+
+   #[test]
+   fn test_translate_replaygain_vorbis_comments_to_id3() {
+       let expected = Some(Frame::with_content("TXXX", Content::ExtendedText(ExtendedText {
+           description: String::from("replaygain_track_gain"),
+           value: String::from("-7.45 dB")
+       })));
+       let actual = translate_vorbis_comment_to_id3(&String::from("REPLAYGAIN_TRACK_GAIN"), &String::from("-7.45 dB"), "eng");
+       assert_eq!(expected, actual);
+   }
+
+   #[test]
+   fn test_relative_volume_adjustment() {
+       let expected: Vec<u8> = vec![b't', b'r', b'a', b'c', b'k', 0, 1, 0xF8, 0x00, 16, 0x40, 0x00];
+       assert_eq!(expected, relative_volume_adjustment_body("track", -4.0, Some(0.5)));
+
+       let expected: Vec<u8> = vec![b'a', b'l', b'b', b'u', b'm', 0, 1, 0x02, 0x00, 0];
+       assert_eq!(expected, relative_volume_adjustment_body("album", 1.0, None));
+   }
+
+   #[test]
+   fn test_insert_raw_frames() {
+       // ID3v2.4 tag holding nothing but 2 bytes of padding
+       let mut tag_bytes: Vec<u8> = vec![b'I', b'D', b'3', 4, 0, 0, 0, 0, 0, 2, 0, 0];
+       let frame = encode_raw_frame("RVA2", &[0xAB; 200], Version::Id3v24);
+       assert_eq!(vec![b'R', b'V', b'A', b'2', 0, 0, 1, 0x48, 0, 0], frame[..10].to_vec());
+
+       insert_raw_frames(&mut tag_bytes, &frame);
+       assert_eq!(222, tag_bytes.len());
+       // 2 + 210 bytes, synchsafe
+       assert_eq!(vec![0, 0, 1, 0x54], tag_bytes[6..10].to_vec());
+       assert_eq!(frame, tag_bytes[10..220].to_vec());
+       assert_eq!(vec![0, 0], tag_bytes[220..].to_vec());
+
+       let frame = encode_raw_frame("RVA2", &[0xAB; 200], Version::Id3v23);
+       assert_eq!(vec![b'R', b'V', b'A', b'2', 0, 0, 0, 200, 0, 0], frame[..10].to_vec());
+   }
+
+   #[test]
+   fn test_chapter_frames() {
+       let chapters = vec![
+           Chapter { title: String::from("a"), start: 0, end: 1500 },
+           Chapter { title: String::from("b"), start: 1500, end: 4000 },
+       ];
+       let frames = chapter_frames(&chapters, Version::Id3v23);
+
+       let table_of_contents = b"CTOC\x00\x00\x00\x10\x00\x00toc\x00\x03\x02chp0\x00chp1\x00";
+       assert_eq!(table_of_contents.to_vec(), frames[..26].to_vec());
+
+       let mut chapter: Vec<u8> = b"CHAP\x00\x00\x00\x24\x00\x00chp1\x00".to_vec();
+       chapter.extend_from_slice(&[0, 0, 0x05, 0xDC, 0, 0, 0x0F, 0xA0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+       chapter.extend_from_slice(b"TIT2\x00\x00\x00\x05\x00\x00\x01\xFF\xFEb\x00");
+       assert_eq!(chapter, frames[frames.len() - chapter.len()..].to_vec());
+       assert_eq!(26 + 2 * chapter.len(), frames.len());
+   }
+
+   #[test]
+   fn test_find_text_placeholder() {
+       // Single byte encoded TXXX body
+       let tag_bytes = b"ID3\x03\x00\x00\x00\x00\x00\x00TXXX\x00\x00\x00\x0A\x00\x00\x00gain\x000.00";
+       let placeholder = find_text_placeholder(tag_bytes, "gain", "0.00").unwrap();
+       assert_eq!(26, placeholder.offset);
+       assert_eq!(Some(b"1.25".to_vec()), placeholder.encode("1.25"));
+       assert_eq!(None, placeholder.encode("1.250"));
+
+       // UTF-16 encoded TXXX body
+       let tag_bytes = vec![
+           0x01, 0xFF, 0xFE, b'g', 0, 0, 0, 0xFF, 0xFE, b'0', 0, b'.', 0, b'0', 0
+       ];
+       let placeholder = find_text_placeholder(&tag_bytes, "g", "0.0").unwrap();
+       assert_eq!(9, placeholder.offset);
+       assert_eq!(Some(vec![b'1', 0, b'.', 0, b'5', 0]), placeholder.encode("1.5"));
+
+       assert_eq!(None, find_text_placeholder(&tag_bytes, "peak", "0.0"));
+   }
+
+   #[test]
+   fn test_transcode_frames() {
+       let mut md5sum = [0u8; 16];
+       md5sum[0] = 0xAB;
+       md5sum[15] = 0x01;
+
+       let frames = transcode_frames("LAME 3.100 -V0 vbr_mtrh", 24, 96000, &md5sum);
+       assert_eq!(6, frames.len());
+       assert_eq!(&Content::Text(String::from("LAME 3.100 -V0 vbr_mtrh")), frames[0].content());
+       assert_eq!("TENC", frames[1].id());
+       assert_eq!(&Content::ExtendedText(ExtendedText {
+           description: String::from("Source bit depth"),
+           value: String::from("24")
+       }), frames[3].content());
+       assert_eq!(&Content::ExtendedText(ExtendedText {
+           description: String::from("Source MD5"),
+           value: String::from("ab000000000000000000000000000001")
+       }), frames[5].content());
+
+       // No MD5 in STREAMINFO
+       assert_eq!(5, transcode_frames("LAME 3.100 -V0 vbr_mtrh", 16, 44100, &[0u8; 16]).len());
+   }
+
+   #[test]
+   fn test_itunes_smpb() {
+       // 576 samples LAME delay, 100 frames holding 113000 samples
+       let expected = " 00000000 00000451 00000447 000000000001B968 \
+           00000000 00000000 00000000 00000000 00000000 00000000 00000000 00000000";
+       assert_eq!(expected, itunes_smpb(576, 1152, 100, 113000));
+       assert_eq!(expected.len(), itunes_smpb(0, 0, 0, 0).len());
+
+       // MPEG-2 frames hold 576 samples
+       let expected = " 00000000 00000451 000000FB 000000000000DBB4 \
+           00000000 00000000 00000000 00000000 00000000 00000000 00000000 00000000";
+       assert_eq!(expected, itunes_smpb(576, 576, 100, 56244));
+   }
+
+   #[test]
+   fn test_synchronised_lyrics_frame() {
+       let lines = vec![
+           LyricLine { timestamp: 1000, text: String::from("a") },
+           LyricLine { timestamp: 258, text: String::from("") },
+       ];
+
+       let expected: Vec<u8> = vec![
+           1, b'e', b'n', b'g', 2, 1,
+           0xFF, 0xFE, 0, 0,
+           0xFF, 0xFE, b'a', 0, 0, 0, 0, 0, 0x03, 0xE8,
+           0xFF, 0xFE, 0, 0, 0, 0, 0x01, 0x02
+       ];
+       let actual = synchronised_lyrics_frame(&lines, "eng");
+       assert_eq!("SYLT", actual.id());
+       assert_eq!(&Content::Unknown(expected), actual.content());
    }
 }
@@ -0,0 +1,125 @@
+const MP3_EXTENSION: &'static str = ".mp3";
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Literal(String),
+    /// A vorbis comment, optionally zero padded to a width if it's numeric
+    Field { name: String, width: Option<usize> }
+}
+
+/// Template building MP3 names from vorbis comments, e.g. `{tracknumber:02} - {artist} - {title}.mp3`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NameTemplate {
+    parts: Vec<Part>
+}
+
+impl NameTemplate {
+
+    pub fn parse(template: &str) -> Result<NameTemplate, String> {
+        // The extension is always added when rendering
+        let extension_start = template.len().saturating_sub(MP3_EXTENSION.len());
+        let template = match template.get(extension_start..) {
+            Some(extension) if extension.eq_ignore_ascii_case(MP3_EXTENSION) => &template[..extension_start],
+            _ => template
+        };
+
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find("{") {
+            if start > 0 {
+                parts.push(Part::Literal(String::from(&rest[..start])));
+            }
+            let end = match rest[start..].find("}") {
+                Some(end) => start + end,
+                None => return Err(format!("Unterminated field in name template {}", template))
+            };
+
+            let field = &rest[start + 1..end];
+            let (name, width) = match field.find(":") {
+                Some(index) => match field[index + 1..].parse() {
+                    Ok(width) => (&field[..index], Some(width)),
+                    Err(_) => return Err(format!("Invalid width in name template field {}", field))
+                },
+                None => (field, None)
+            };
+            if name.is_empty() {
+                return Err(format!("Empty field in name template {}", template));
+            }
+            parts.push(Part::Field { name: name.to_uppercase(), width });
+
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(String::from(rest)));
+        }
+
+        Ok(NameTemplate { parts })
+    }
+
+    /// Builds a file name from the provided vorbis comments. Missing comments render as empty
+    /// strings. Returns None if none of the fields had a value.
+    pub fn render(&self, comments: &[(String, String)]) -> Option<String> {
+        let mut name = String::new();
+        let mut has_value = false;
+
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => name.push_str(literal),
+                Part::Field { name: field_name, width } => {
+                    let value = comments.iter()
+                        .find(|(key, value)| key.eq_ignore_ascii_case(field_name) && !value.trim().is_empty())
+                        .map(|(_, value)| value.trim());
+                    if let Some(value) = value {
+                        has_value = true;
+                        name.push_str(&format_value(value, *width).replace("/", "_").replace("\0", ""));
+                    }
+                }
+            }
+        }
+
+        if !has_value {
+            return None;
+        }
+        Some(format!("{}{}", name.trim(), MP3_EXTENSION))
+    }
+}
+
+/// Zero pads numeric values such as track numbers, which may be given as e.g. "3/12".
+fn format_value(value: &str, width: Option<usize>) -> String {
+    let width = match width {
+        Some(width) => width,
+        None => return String::from(value)
+    };
+
+    match value.split("/").next().unwrap_or(value).trim().parse::<u32>() {
+        Ok(number) => format!("{:0width$}", number, width = width),
+        Err(_) => String::from(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::template::NameTemplate;
+
+    #[test]
+    fn test_render() {
+        let template = NameTemplate::parse("{tracknumber:02} - {artist} - {title}.mp3").unwrap();
+        let comments = vec![
+            (String::from("TRACKNUMBER"), String::from("3/12")),
+            (String::from("artist"), String::from("AC/DC")),
+            (String::from("TITLE"), String::from("test_title")),
+        ];
+        assert_eq!(Some(String::from("03 - AC_DC - test_title.mp3")), template.render(&comments));
+
+        let template = NameTemplate::parse("{discnumber}-{tracknumber:3} {title}").unwrap();
+        assert_eq!(Some(String::from("-003 test_title.mp3")), template.render(&comments));
+        assert_eq!(None, template.render(&[]));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(NameTemplate::parse("{title").is_err());
+        assert!(NameTemplate::parse("{title:wide}").is_err());
+        assert!(NameTemplate::parse("{} - {title}").is_err());
+    }
+}
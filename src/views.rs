@@ -0,0 +1,354 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::fs::read_dir;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use claxon::FlacReader;
+use crate::encode;
+use crate::mp3v0fs::{FLAC, has_extension, presented_name};
+use crate::sanitize::{SanitizeMode, numbered};
+use crate::template::NameTemplate;
+
+/// Top-level directories of the virtual views.
+pub const BY_ARTIST: &'static str = ".by-artist";
+pub const BY_GENRE: &'static str = ".by-genre";
+pub const BY_YEAR: &'static str = ".by-year";
+pub const VIEWS: [&'static str; 3] = [BY_ARTIST, BY_GENRE, BY_YEAR];
+
+/// An entry of a directory within the views.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LibraryEntry {
+    Directory,
+    /// A track, backed by the FLAC at the contained path
+    Track(PathBuf)
+}
+
+/// Metadata used to place a track within the views.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TrackMetadata {
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub genres: Vec<String>,
+    pub year: Option<String>
+}
+
+impl TrackMetadata {
+
+    pub fn from_vorbis_comments<'a, I>(comments: I) -> TrackMetadata
+        where I: Iterator<Item = (&'a str, &'a str)> {
+        let mut metadata = TrackMetadata::default();
+        let mut album_artist: Option<String> = None;
+
+        for (name, value) in comments {
+            match name.to_uppercase().as_ref() {
+                "ALBUMARTIST" | "ALBUM ARTIST" => { album_artist.get_or_insert(String::from(value)); },
+                "ARTIST" => { metadata.artist.get_or_insert(String::from(value)); },
+                "ALBUM" => { metadata.album.get_or_insert(String::from(value)); },
+                "GENRE" => metadata.genres.push(String::from(value)),
+                "DATE" | "YEAR" => if metadata.year.is_none() {
+                    metadata.year = parse_year(value);
+                },
+                _ => ()
+            }
+        }
+
+        // Albums are grouped under the album artist, so compilations stay together
+        if album_artist.is_some() {
+            metadata.artist = album_artist;
+        }
+        metadata
+    }
+}
+
+/// Virtual directory trees presenting the source FLACs by artist, genre and year:
+/// `/.by-artist/<Artist>/<Album>/`, `/.by-genre/<Genre>/<Artist> - <Album>/` and
+/// `/.by-year/<Year>/<Artist> - <Album>/`.
+#[derive(Debug)]
+pub struct Library {
+    sanitize: SanitizeMode,
+    // Entries of each view directory, keyed by its path under the mountpoint
+    directories: HashMap<PathBuf, BTreeMap<String, LibraryEntry>>,
+    // Names of the entries of each view directory by collision key
+    names: HashMap<PathBuf, HashMap<OsString, String>>
+}
+
+impl Library {
+
+    /// Creates a library holding nothing but the empty views. Names are presented according to
+    /// `sanitize`.
+    pub fn new(sanitize: SanitizeMode) -> Library {
+        let mut library = Library {
+            sanitize,
+            directories: HashMap::new(),
+            names: HashMap::new()
+        };
+        for view in VIEWS.iter() {
+            library.add_entry(Path::new("/"), view, LibraryEntry::Directory);
+        }
+        library
+    }
+
+    /// Returns the entries of a view directory, or None if the path isn't one.
+    pub fn list(&self, directory: &Path) -> Option<&BTreeMap<String, LibraryEntry>> {
+        if directory == Path::new("/") {
+            return None;
+        }
+        self.directories.get(directory)
+    }
+
+    /// Returns whether the path is a view directory.
+    pub fn is_directory(&self, path: &Path) -> bool {
+        path != Path::new("/") && self.directories.contains_key(path)
+    }
+
+    /// Returns the entry at the provided path within the views.
+    pub fn get(&self, path: &Path) -> Option<&LibraryEntry> {
+        let name = path.file_name()?.to_str()?;
+        self.directories.get(path.parent()?)?.get(name)
+    }
+
+    /// Adds the FLAC at `flac_path` to every view it belongs in. Tracks are named as in their own
+    /// directory under the mountpoint, from `template_name` if the name template rendered one.
+    pub fn add_track(&mut self, flac_path: &Path, metadata: &TrackMetadata, template_name: Option<String>) {
+        let file_name = presented_name(flac_path, template_name, self.sanitize).to_string_lossy().into_owned();
+        let artist = component(&metadata.artist, "Unknown Artist");
+        let album = component(&metadata.album, "Unknown Album");
+        let artist_album = self.sanitize.apply(&format!("{} - {}", artist, album));
+        let artist = self.sanitize.apply(&artist);
+        let album = self.sanitize.apply(&album);
+
+        let mut album_directories = vec![vec![BY_ARTIST, &artist, &album]];
+        if metadata.genres.is_empty() {
+            album_directories.push(vec![BY_GENRE, "Unknown Genre", &artist_album]);
+        }
+        let genres: Vec<String> = metadata.genres.iter()
+            .map(|genre| self.sanitize.apply(&component(&Some(genre.clone()), "Unknown Genre")))
+            .collect();
+        for genre in &genres {
+            album_directories.push(vec![BY_GENRE, genre, &artist_album]);
+        }
+        let year = self.sanitize.apply(&component(&metadata.year, "Unknown Year"));
+        album_directories.push(vec![BY_YEAR, &year, &artist_album]);
+
+        for components in album_directories {
+            let mut directory = PathBuf::from("/");
+            for component in components {
+                let name = self.add_entry(&directory, component, LibraryEntry::Directory);
+                directory.push(name);
+            }
+            self.add_entry(&directory, &file_name, LibraryEntry::Track(flac_path.to_path_buf()));
+        }
+    }
+
+    /// Adds an entry to a view directory and returns its name. Names colliding with a different
+    /// entry, e.g. tracks sharing a file name within one album, are numbered the same way as under
+    /// the mountpoint. Directories colliding with another, e.g. genres differing only in case with
+    /// `sanitize=vfat`, are merged.
+    fn add_entry(&mut self, directory: &Path, name: &str, entry: LibraryEntry) -> String {
+        let sanitize_mode = self.sanitize;
+        let entries = self.directories.entry(directory.to_path_buf()).or_insert_with(BTreeMap::new);
+        let names = self.names.entry(directory.to_path_buf()).or_insert_with(HashMap::new);
+        let mut unique_name = String::from(name);
+        let mut counter = 1;
+        while let Some(existing_name) = names.get(&sanitize_mode.collision_key(OsStr::new(&unique_name))) {
+            if entries.get(existing_name) == Some(&entry) {
+                return existing_name.clone();
+            }
+            counter += 1;
+            unique_name = numbered(OsStr::new(name), counter).to_string_lossy().into_owned();
+        }
+        names.insert(sanitize_mode.collision_key(OsStr::new(&unique_name)), unique_name.clone());
+        entries.insert(unique_name.clone(), entry.clone());
+
+        if entry == LibraryEntry::Directory {
+            self.directories.entry(directory.join(&unique_name)).or_insert_with(BTreeMap::new);
+        }
+        unique_name
+    }
+}
+
+/// Starts a background job scanning every FLAC below `root` into a new library, which replaces the
+/// contents of `library` once done. Tracks are named from `name_template` if set. FLACs for which
+/// `exclude` returns true are left out.
+pub fn spawn_scan<F>(root: PathBuf, library: Arc<Mutex<Library>>, name_template: Option<NameTemplate>, exclude: F) -> JoinHandle<()>
+    where F: Fn(&Path) -> bool + Send + 'static {
+    thread::spawn(move || {
+        info!("Starting library scan of {:?}", root);
+        let sanitize = library.lock().unwrap().sanitize;
+        let mut scanned = Library::new(sanitize);
+        scan_directory(&root, &mut scanned, &name_template, &exclude);
+        *library.lock().unwrap() = scanned;
+        info!("Finished library scan of {:?}", root);
+    })
+}
+
+fn scan_directory<F: Fn(&Path) -> bool>(directory: &Path, library: &mut Library, name_template: &Option<NameTemplate>, exclude: &F) {
+    let entries = match read_dir(directory) {
+        Ok(entries) => entries,
+        Err(err) => {
+            warn!("Unable to scan {:?} for the library: {}", directory, err);
+            return;
+        }
+    };
+
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => scan_directory(&path, library, name_template, exclude),
+            Ok(file_type) if file_type.is_file() && has_extension(&path, FLAC) && !exclude(&path) => {
+                match FlacReader::open(&path) {
+                    Ok(flac_reader) => {
+                        let template_name = name_template.as_ref()
+                            .and_then(|name_template| name_template.render(&encode::vorbis_comments(&flac_reader)));
+                        library.add_track(&path, &TrackMetadata::from_vorbis_comments(flac_reader.tags()), template_name);
+                    },
+                    Err(err) => warn!("Unable to read tags of {:?}: {}", path, err)
+                }
+            },
+            _ => ()
+        }
+    }
+}
+
+/// Takes the year from dates such as "2019-05-01".
+fn parse_year(date: &str) -> Option<String> {
+    let year: String = date.trim().chars().take(4).collect();
+    if year.len() == 4 && year.chars().all(|c| c.is_ascii_digit()) {
+        Some(year)
+    } else {
+        None
+    }
+}
+
+/// Turns a tag value into a usable path component.
+fn component(value: &Option<String>, default: &str) -> String {
+    match value {
+        Some(value) if !sanitize(value).trim().is_empty() => sanitize(value).trim().to_owned(),
+        _ => String::from(default)
+    }
+}
+
+fn sanitize(value: &str) -> String {
+    value.replace("/", "_").replace("\0", "")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sanitize::SanitizeMode;
+    use crate::template::NameTemplate;
+    use crate::views::{Library, LibraryEntry, TrackMetadata};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_from_vorbis_comments() {
+        let comments = vec![
+            ("ARTIST", "test_artist"),
+            ("albumartist", "test_album_artist"),
+            ("ALBUM", "test_album"),
+            ("GENRE", "Rock"),
+            ("GENRE", "Pop"),
+            ("DATE", "2019-05-01"),
+        ];
+
+        let expected = TrackMetadata {
+            artist: Some(String::from("test_album_artist")),
+            album: Some(String::from("test_album")),
+            genres: vec![String::from("Rock"), String::from("Pop")],
+            year: Some(String::from("2019"))
+        };
+        assert_eq!(expected, TrackMetadata::from_vorbis_comments(comments.into_iter()));
+    }
+
+    #[test]
+    fn test_add_track() {
+        let mut library = Library::new(SanitizeMode::None);
+        let flac_path = PathBuf::from("/music/label/cat001/01 - a.flac");
+        library.add_track(&flac_path, &TrackMetadata {
+            artist: Some(String::from("AC/DC")),
+            album: Some(String::from("test_album")),
+            genres: vec![String::from("Rock")],
+            year: None
+        }, None);
+
+        let track = LibraryEntry::Track(flac_path.clone());
+        assert_eq!(Some(&track), library.get(Path::new("/.by-artist/AC_DC/test_album/01 - a.mp3")));
+        assert_eq!(Some(&track), library.get(Path::new("/.by-genre/Rock/AC_DC - test_album/01 - a.mp3")));
+        assert_eq!(Some(&track), library.get(Path::new("/.by-year/Unknown Year/AC_DC - test_album/01 - a.mp3")));
+        assert_eq!(Some(&LibraryEntry::Directory), library.get(Path::new("/.by-artist/AC_DC")));
+
+        let artists: Vec<&String> = library.list(Path::new("/.by-artist")).unwrap().keys().collect();
+        assert_eq!(vec!["AC_DC"], artists);
+        assert_eq!(None, library.list(Path::new("/")));
+        assert_eq!(None, library.list(Path::new("/label")));
+        assert!(library.is_directory(Path::new("/.by-artist/AC_DC")));
+        assert!(!library.is_directory(Path::new("/.by-artist/AC_DC/test_album/01 - a.mp3")));
+        assert!(!library.is_directory(Path::new("/")));
+
+        // Tracks of an album sharing a file name are numbered
+        let other_flac_path = PathBuf::from("/music/label/cat001/cd2/01 - a.flac");
+        library.add_track(&other_flac_path, &TrackMetadata {
+            artist: Some(String::from("AC/DC")),
+            album: Some(String::from("test_album")),
+            genres: vec![String::from("Rock")],
+            year: None
+        }, None);
+        assert_eq!(Some(&track), library.get(Path::new("/.by-artist/AC_DC/test_album/01 - a.mp3")));
+        assert_eq!(
+            Some(&LibraryEntry::Track(other_flac_path)),
+            library.get(Path::new("/.by-artist/AC_DC/test_album/01 - a (2).mp3"))
+        );
+    }
+
+    #[test]
+    fn test_add_track_vfat() {
+        let mut library = Library::new(SanitizeMode::Vfat);
+        let flac_path = PathBuf::from("/music/album/A.flac");
+        let other_flac_path = PathBuf::from("/music/album/a.flac");
+        let metadata = |genre: &str| TrackMetadata {
+            artist: Some(String::from("test_artist")),
+            album: Some(String::from("test_album")),
+            genres: vec![String::from(genre)],
+            year: None
+        };
+        library.add_track(&flac_path, &metadata("Rock"), None);
+        library.add_track(&other_flac_path, &metadata("rock"), None);
+
+        // Names differing only in case collide on vfat
+        let genres: Vec<&String> = library.list(Path::new("/.by-genre")).unwrap().keys().collect();
+        assert_eq!(vec!["Rock"], genres);
+        let tracks: Vec<&String> = library.list(Path::new("/.by-artist/test_artist/test_album")).unwrap()
+            .keys()
+            .collect();
+        assert_eq!(vec!["A.mp3", "a (2).mp3"], tracks);
+        assert_eq!(
+            Some(&LibraryEntry::Track(other_flac_path)),
+            library.get(Path::new("/.by-genre/Rock/test_artist - test_album/a (2).mp3"))
+        );
+    }
+
+    #[test]
+    fn test_add_track_name_template() {
+        let mut library = Library::new(SanitizeMode::Vfat);
+        let flac_path = PathBuf::from("/music/album/01 - a.flac");
+        let metadata = TrackMetadata {
+            artist: Some(String::from("test_artist")),
+            album: Some(String::from("test_album")),
+            genres: Vec::new(),
+            year: None
+        };
+        let name_template = NameTemplate::parse("{tracknumber:02}. {title}").unwrap();
+        let comments = vec![
+            (String::from("TRACKNUMBER"), String::from("1")),
+            (String::from("TITLE"), String::from("What?")),
+        ];
+        library.add_track(&flac_path, &metadata, name_template.render(&comments));
+
+        // Tracks are named like in their own directory, templated and sanitized
+        assert_eq!(
+            Some(&LibraryEntry::Track(flac_path)),
+            library.get(Path::new("/.by-artist/test_artist/test_album/01. What_.mp3"))
+        );
+    }
+}
@@ -1,4 +1,5 @@
 use mp3v0fs::run_async;
+use mp3v0fs::options::Options;
 
 use std::ffi::{OsString, OsStr};
 use std::fs::{read_dir, File};
@@ -23,7 +24,7 @@ fn test_filesystem() -> Result<(), Error> {
         &OsStr::new("-o"), &OsStr::new("rdonly")
     ];
 
-    let fs_session = run_async(&target_dir_path, &mount_dir_path, &fuse_args);
+    let fs_session = run_async(&target_dir_path, &mount_dir_path, &Options::default(), &fuse_args);
     thread::sleep(Duration::from_millis(50));
 
     {